@@ -1,14 +1,9 @@
-mod bytecode;
-
 use std::collections::HashMap;
-use std::convert::TryInto;
-use std::fs::File;
-use std::io::BufReader;
-use std::io::Read;
-use std::io::Write;
-use std::mem::size_of;
+use std::convert::TryFrom;
 
-use bytecode::Inst;
+use crate::bytecode::Inst;
+use crate::container::{FunctionInfo, LineInfo, Program, Symbol};
+use crate::vm::NUM_REGS;
 
 /// Enumeration Tag represents token types except for symbols such {, }, etc.
 enum Tag {
@@ -45,21 +40,21 @@ impl WordBase {
 
 impl PartialEq for WordBase {
     fn eq(&self, other: &Self) -> bool {
-        if (*self).token.tag != (*other).token.tag {
-            false;
+        if self.token.tag != other.token.tag {
+            return false;
         }
-        (*self).lexeme == (*other).lexeme
+        self.lexeme == other.lexeme
     }
 }
 
 #[derive(Clone)]
 struct Num {
     token: TokenBase,
-    value: u32,
+    value: i64,
 }
 
 impl Num {
-    fn new(v: u32) -> Num {
+    fn new(v: i64) -> Num {
         Num {
             token: TokenBase {
                 tag: Tag::Num as u32,
@@ -70,10 +65,13 @@ impl Num {
 }
 
 #[derive(Clone)]
+#[allow(clippy::enum_variant_names)]
 enum Token {
     Token(TokenBase),
     Word(WordBase),
     Num(Num),
+    /// Malformed token with the explanation
+    Error(String),
     Eof,
 }
 
@@ -85,101 +83,214 @@ impl Token {
 
     #[allow(dead_code)]
     fn get_tag(&self) -> Option<u32> {
-        match &*self {
+        match self {
             Token::Token(tok) => Some(tok.tag),
             Token::Word(word) => Some(word.token.tag),
             Token::Num(num) => Some(num.token.tag),
-            Token::Eof => None,
+            Token::Error(_) | Token::Eof => None,
         }
     }
+}
 
-    fn to_string(&self) -> String {
-        match &*self {
-            Token::Token(tok) => {
-                let mut s = String::new();
-                s.push(std::char::from_u32(tok.tag).unwrap());
-                s
-            }
-            Token::Word(word) => word.lexeme.clone(),
-            Token::Num(num) => format!("{}", num.value),
-            _ => "Eof".to_string(),
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Token(tok) => write!(f, "{}", std::char::from_u32(tok.tag).unwrap()),
+            Token::Word(word) => write!(f, "{}", word.lexeme),
+            Token::Num(num) => write!(f, "{}", num.value),
+            Token::Error(message) => write!(f, "{}", message),
+            Token::Eof => write!(f, "Eof"),
         }
     }
 }
 
+/// Position of a token in the source: 1-based line and column and the length in characters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    pub line: u32,
+    pub column: u32,
+    pub len: u32,
+}
+
+impl Span {
+    /// Empty position right after the span.
+    fn after(self) -> Span {
+        Span {
+            line: self.line,
+            column: self.column + self.len,
+            len: 1,
+        }
+    }
+}
+
+/// Error in an assembly source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub file: String,
+    pub span: Span,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Render the diagnostic the way rustc does, with the offending line of `source` and a caret
+    /// under the span.
+    pub fn render(&self, source: &str) -> String {
+        let text = source
+            .lines()
+            .nth(self.span.line as usize - 1)
+            .unwrap_or("");
+        let line = self.span.line.to_string();
+        let gutter = " ".repeat(line.len());
+        // Keep the tabs so that the caret lines up with the source line
+        let indent: String = text
+            .chars()
+            .take(self.span.column as usize - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        format!(
+            "error: {}\n{}--> {}:{}:{}\n{} |\n{} | {}\n{} | {}{}",
+            self.message,
+            gutter,
+            self.file,
+            self.span.line,
+            self.span.column,
+            gutter,
+            line,
+            text,
+            gutter,
+            indent,
+            "^".repeat(self.span.len.max(1) as usize)
+        )
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.file, self.span.line, self.span.column, self.message
+        )
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
 struct Lexer {
-    buf_reader: BufReader<File>,
+    source: Vec<char>,
+    pos: usize,
     line_num: u32, // uses for syntax error reports
+    line_start: usize,
     peek: char,
+    peek_index: usize,
     eof: bool,
+    /// Position of the last scanned token
+    span: Span,
 }
 
 impl Lexer {
-    fn new(file_name: &str) -> Lexer {
-        let lex = Lexer {
-            buf_reader: BufReader::new(File::open(file_name).expect("open failed")),
+    fn new(source: &str) -> Lexer {
+        Lexer {
+            source: source.chars().collect(),
+            pos: 0,
             line_num: 1,
+            line_start: 0,
             peek: ' ',
+            peek_index: 0,
             eof: false,
-        };
-
-        lex
+            span: Span {
+                line: 1,
+                column: 1,
+                len: 0,
+            },
+        }
     }
 
     fn read_char(&mut self) {
-        let mut buffer = [0; 1];
-        match self.buf_reader.read(&mut buffer) {
-            Ok(x) => {
-                if x != 0 {
-                    self.peek = buffer[0] as char;
-                } else {
-                    self.eof = true;
-                }
+        self.peek_index = self.pos;
+        match self.source.get(self.pos) {
+            Some(c) => {
+                self.peek = *c;
+                self.pos += 1;
             }
-            Err(_y) => panic!("read() failed{}", _y),
-        };
+            None => {
+                // Terminates a token which ends the source
+                self.peek = ' ';
+                self.eof = true;
+            }
+        }
+    }
+
+    fn column(&self, index: usize) -> u32 {
+        (index - self.line_start) as u32 + 1
     }
 
     fn scan(&mut self) -> Token {
         loop {
-            if self.peek == ' ' || self.peek == '\t' {
-                ()
-            } else if self.peek == '\n' {
-                self.line_num = self.line_num + 1;
-            } else {
+            if self.peek == '\n' {
+                self.line_num += 1;
+                self.line_start = self.pos;
+            } else if self.peek == ';' || self.peek == '#' {
+                // Comments run to the end of the line
+                while {
+                    self.read_char();
+                    !(self.eof || self.peek == '\n')
+                } {}
+                continue;
+            } else if self.peek != ' ' && self.peek != '\t' && self.peek != '\r' {
                 break;
             }
 
             self.read_char();
 
             if self.eof {
+                self.span = Span {
+                    line: self.line_num,
+                    column: self.column(self.peek_index),
+                    len: 1,
+                };
                 return Token::Eof;
             }
         }
 
-        // Number handling
-        if self.peek.is_digit(10) {
-            let mut v: u32 = 0;
-            loop {
-                v = 10 * v + self.peek.to_digit(10).unwrap();
-                self.read_char();
-                if !self.peek.is_digit(10) {
-                    break;
-                }
-            }
-            if self.peek != '.' {
-                return Token::Num(Num::new(v));
+        let start = self.peek_index;
+        let tok = self.token();
+        let end = match tok {
+            Token::Token(_) => start + 1,
+            _ => self.peek_index,
+        };
+        self.span = Span {
+            line: self.line_num,
+            column: self.column(start),
+            len: (end - start) as u32,
+        };
+        tok
+    }
+
+    fn token(&mut self) -> Token {
+        if self.peek.is_ascii_digit() {
+            return self.number(false);
+        }
+        if self.peek == '-' {
+            self.read_char();
+            if self.peek.is_ascii_digit() {
+                return self.number(true);
             }
+            return Token::Token(TokenBase::new('-' as u32));
+        }
+        if self.peek == '\'' {
+            return self.character();
         }
 
         // Word handle
-        if self.peek.is_alphabetic() {
+        if is_ident_start(self.peek) {
             let mut s = String::new();
             loop {
                 s.push(self.peek);
                 self.read_char();
 
-                if !(self.peek.is_alphabetic() || self.peek.is_digit(10)) {
+                if !is_ident_char(self.peek) {
                     break;
                 }
             }
@@ -197,178 +308,820 @@ impl Lexer {
         self.peek = ' ';
         tok
     }
-}
 
-fn handle_reg(v: Token) -> u8 {
-    let s = match v {
-        Token::Word(word) => word.lexeme.clone(),
-        _ => panic!("This token is not a Word"),
-    };
-    let mut num = String::new();
+    /// Integer literal: decimal, or hexadecimal, binary and octal with the `0x`, `0b` and `0o`
+    /// prefixes. Digits can be separated with underscores.
+    fn number(&mut self, negative: bool) -> Token {
+        let mut s = String::new();
+        while is_ident_char(self.peek) {
+            s.push(self.peek);
+            self.read_char();
+        }
+        let literal = format!("{}{}", if negative { "-" } else { "" }, s);
 
-    for (pos, c) in s.char_indices() {
-        if pos == 0 {
-            assert!(c == 'v');
-            continue;
+        let (radix, kind, digits) = match s.get(..2) {
+            Some("0x") | Some("0X") => (16, "hexadecimal", &s[2..]),
+            Some("0b") | Some("0B") => (2, "binary", &s[2..]),
+            Some("0o") | Some("0O") => (8, "octal", &s[2..]),
+            _ => (10, "decimal", &s[..]),
+        };
+        let digits: String = digits.chars().filter(|c| *c != '_').collect();
+        if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+            return Token::Error(format!("invalid {} literal `{}`", kind, literal));
+        }
+
+        let value = u64::from_str_radix(&digits, radix)
+            .ok()
+            .and_then(|v| i64::try_from(v).ok())
+            .map(|v| if negative { -v } else { v });
+        match value {
+            Some(v) => Token::Num(Num::new(v)),
+            None => Token::Error(format!("integer literal `{}` is too large", literal)),
         }
-        assert!(c.is_digit(10));
-        num.push(c);
     }
-    num.parse::<u8>().unwrap()
+
+    /// Character literal such as 'a' or '\n', its value is the code point.
+    fn character(&mut self) -> Token {
+        self.read_char();
+        let c = match self.peek {
+            _ if self.eof => return Token::Error("unterminated character literal".to_string()),
+            '\'' | '\n' => return Token::Error("empty character literal".to_string()),
+            '\\' => {
+                self.read_char();
+                let c = match self.peek {
+                    'n' => '\n',
+                    't' => '\t',
+                    'r' => '\r',
+                    '0' => '\0',
+                    '\\' => '\\',
+                    '\'' => '\'',
+                    c => {
+                        let message = format!("unknown character escape `\\{}`", c);
+                        self.read_char();
+                        return Token::Error(message);
+                    }
+                };
+                c
+            }
+            c => c,
+        };
+
+        self.read_char();
+        if self.eof || self.peek != '\'' {
+            return Token::Error("unterminated character literal".to_string());
+        }
+        self.read_char();
+        Token::Num(Num::new(c as i64))
+    }
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_' || c == '.'
 }
 
-fn handle_imm(imm: Token) -> u32 {
-    match &imm {
-        Token::Num(num) => num.value,
-        _ => panic!("This token is not a Num, it is {}", imm.to_string()),
+fn is_ident_char(c: char) -> bool {
+    is_ident_start(c) || c.is_ascii_digit()
+}
+
+/// Use of a label or a function name by the instruction at `index`.
+struct Fixup {
+    index: usize,
+    name: String,
+    span: Span,
+}
+
+/// Constructor of the conditional branch instruction with mnemonic `mnem`.
+fn cond_branch(mnem: &str) -> Option<fn(u8, u8, u32) -> Inst> {
+    match mnem {
+        "bne" => Some(Inst::Bne),
+        "beq" => Some(Inst::Beq),
+        "blt" => Some(Inst::Blt),
+        "ble" => Some(Inst::Ble),
+        "bgt" => Some(Inst::Bgt),
+        "bge" => Some(Inst::Bge),
+        "bltu" => Some(Inst::Bltu),
+        "bleu" => Some(Inst::Bleu),
+        "bgtu" => Some(Inst::Bgtu),
+        "bgeu" => Some(Inst::Bgeu),
+        _ => None,
+    }
+}
+
+/// Instruction taking a register operand.
+fn reg_inst(mnem: &str) -> Option<fn(u8) -> Inst> {
+    match mnem {
+        "lda" => Some(Inst::Lda),
+        "sta" => Some(Inst::Sta),
+        "add" => Some(Inst::Add),
+        "dec" => Some(Inst::Dec),
+        "sub" => Some(Inst::Sub),
+        "mul" => Some(Inst::Mul),
+        "div" => Some(Inst::Div),
+        "mod" => Some(Inst::Mod),
+        "and" => Some(Inst::And),
+        "or" => Some(Inst::Or),
+        "xor" => Some(Inst::Xor),
+        "shl" => Some(Inst::Shl),
+        "shr" => Some(Inst::Shr),
+        "sar" => Some(Inst::Sar),
+        _ => None,
+    }
+}
+
+/// Instruction taking an immediate operand.
+fn imm_inst(mnem: &str) -> Option<fn(u32) -> Inst> {
+    match mnem {
+        "ldai" => Some(Inst::Ldai),
+        "addi" => Some(Inst::Addi),
+        "subi" => Some(Inst::Subi),
+        "muli" => Some(Inst::Muli),
+        "divi" => Some(Inst::Divi),
+        "modi" => Some(Inst::Modi),
+        _ => None,
     }
 }
 
 struct Parser {
     lex: Lexer,
+    file: String,
+    /// Lookahead token
+    peeked: Option<(Token, Span)>,
+    /// Last consumed token
+    last: Span,
+    /// Line of the statement being parsed, its operands must be on the same line
+    line: u32,
+    /// Difference between the line numbers recorded in the debug info and the source ones
+    line_offset: i64,
+    code: Vec<Inst>,
+    labels: HashMap<String, u32>,
+    // Labels and functions can be defined after their uses, the targets are patched at the end
+    fixups: Vec<Fixup>,
+    /// `.function` directive of the function being parsed
+    function: Option<Span>,
+    symbols: Vec<Symbol>,
+    functions: Vec<FunctionInfo>,
+    debug: Vec<LineInfo>,
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl Parser {
-    fn new(lex: Lexer) -> Parser {
-        Parser { lex: lex }
+    fn new(file: &str, lex: Lexer) -> Parser {
+        let last = lex.span;
+        Parser {
+            lex,
+            file: file.to_string(),
+            peeked: None,
+            last,
+            line: 1,
+            line_offset: 0,
+            code: Vec::new(),
+            labels: HashMap::new(),
+            fixups: Vec::new(),
+            function: None,
+            symbols: Vec::new(),
+            functions: Vec::new(),
+            debug: Vec::new(),
+            diagnostics: Vec::new(),
+        }
     }
 
-    fn match_(&mut self, expect: &str) {
-        if &self.lex.scan().to_string() != expect {
-            panic!("Token does not match the expected one");
+    fn error(&self, span: Span, message: String) -> Diagnostic {
+        Diagnostic {
+            file: self.file.clone(),
+            span,
+            message,
         }
     }
 
-    fn fetch_insts(&mut self) -> Vec<Inst> {
-        let mut ret = Vec::new();
-        let mut labels: HashMap<String, u32> = HashMap::new();
+    fn peek(&mut self) -> &(Token, Span) {
+        if self.peeked.is_none() {
+            let tok = self.lex.scan();
+            self.peeked = Some((tok, self.lex.span));
+        }
+        self.peeked.as_ref().unwrap()
+    }
 
-        loop {
-            let mnemonic_token = self.lex.scan();
-            let mnem = match &mnemonic_token {
-                Token::Eof => break,
-                _ => mnemonic_token.to_string(),
-            };
+    fn next(&mut self) -> (Token, Span) {
+        self.peek();
+        let (tok, span) = self.peeked.take().unwrap();
+        self.last = span;
+        (tok, span)
+    }
 
-            if mnem == "mov".to_string() {
-                let v1 = self.lex.scan();
-                self.match_(",");
-                let v2 = self.lex.scan();
-
-                ret.push(Inst::Mov(handle_reg(v1), handle_reg(v2)));
-            } else if mnem == "movi" {
-                let vr = self.lex.scan();
-                self.match_(",");
-                let imm = self.lex.scan();
-
-                ret.push(Inst::Movi(handle_reg(vr), handle_imm(imm)));
-            } else if mnem == "ldai".to_string() {
-                let imm = self.lex.scan();
-
-                ret.push(Inst::Ldai(handle_imm(imm)));
-            } else if mnem == "lda" {
-                let vr = self.lex.scan();
-
-                ret.push(Inst::Lda(handle_reg(vr)));
-            } else if mnem == "sta" {
-                let vr = self.lex.scan();
-
-                ret.push(Inst::Sta(handle_reg(vr)));
-            } else if mnem == "add" {
-                let vr = self.lex.scan();
-
-                ret.push(Inst::Add(handle_reg(vr)));
-            } else if mnem == "dec" {
-                let vr = self.lex.scan();
-
-                ret.push(Inst::Dec(handle_reg(vr)));
-            } else if mnem == "bne" {
-                let v1 = self.lex.scan();
-                self.match_(",");
-                let v2 = self.lex.scan();
-                self.match_(",");
-                let label = self.lex.scan().to_string();
-
-                if !labels.contains_key(&label) {
-                    panic!("Label {} not found", label);
-                }
+    /// Is the next token a part of the current statement
+    fn at_end_of_line(&mut self) -> bool {
+        let line = self.line;
+        match self.peek() {
+            (Token::Eof, _) => true,
+            (_, span) => span.line != line,
+        }
+    }
 
-                ret.push(Inst::Bne(
-                    handle_reg(v1),
-                    handle_reg(v2),
-                    *labels.get(&label).unwrap() as u32,
-                ));
-            } else if mnem == "print" {
-                ret.push(Inst::Print);
-            } else if mnem.starts_with("L") {
-                labels.insert(mnem, ret.len() as u32);
-                self.lex.scan();
-            } else {
-                panic!("Expected a mnemonic, got {}", mnem,);
-            }
+    /// Next token of the current statement, `what` describes it for the error message.
+    fn operand(&mut self, what: &str) -> Result<(Token, Span), Diagnostic> {
+        if self.at_end_of_line() {
+            return Err(self.error(
+                self.last.after(),
+                format!("expected {}, found end of line", what),
+            ));
+        }
+        match self.next() {
+            (Token::Error(message), span) => Err(self.error(span, message)),
+            next => Ok(next),
         }
+    }
 
-        ret
+    fn match_(&mut self, expect: &str) -> Result<(), Diagnostic> {
+        let (tok, span) = self.operand(&format!("`{}`", expect))?;
+        if tok.to_string() != expect {
+            return Err(self.error(span, format!("expected `{}`, found `{}`", expect, tok)));
+        }
+        Ok(())
     }
-}
 
-fn write_imm(file: &mut File, imm: u32) {
-    let ptr = &imm as *const u32;
-    let slice = unsafe { std::slice::from_raw_parts(ptr as *const u8, size_of::<u32>()) };
-    assert_eq!(file.write(slice).unwrap(), size_of::<u32>());
-}
+    fn reg(&mut self) -> Result<u8, Diagnostic> {
+        let (tok, span) = self.operand("a register")?;
+        let s = tok.to_string();
+        let num = match tok {
+            Token::Word(_) if s.starts_with('v') => &s[1..],
+            _ => "",
+        };
 
-fn write_inst(file: &mut File, inst: Inst) {
-    let opcode = TryInto::<u8>::try_into(inst.clone()).unwrap();
-    file.write(&[opcode]).unwrap();
+        if num.is_empty() || !num.chars().all(|c| c.is_ascii_digit()) {
+            return Err(self.error(span, format!("expected a register, found `{}`", s)));
+        }
+        num.parse::<u8>().map_err(|_| {
+            self.error(
+                span,
+                format!(
+                    "register `{}` is out of range, the last one is v{}",
+                    s,
+                    NUM_REGS - 1
+                ),
+            )
+        })
+    }
 
-    match inst {
-        Inst::Mov(v1, v2) => {
-            file.write(&[v1, v2]).unwrap();
+    /// Integer operand in the range `min..=max`, `what` describes it for the error messages.
+    fn int(&mut self, what: &str, min: i64, max: i64) -> Result<i64, Diagnostic> {
+        match self.operand(what)? {
+            (Token::Num(num), _) if (min..=max).contains(&num.value) => Ok(num.value),
+            (Token::Num(num), span) => Err(self.error(
+                span,
+                format!(
+                    "{} `{}` is out of range, expected {} to {}",
+                    what, num.value, min, max
+                ),
+            )),
+            (tok, span) => Err(self.error(span, format!("expected {}, found `{}`", what, tok))),
         }
+    }
 
-        Inst::Movi(v, imm) => {
-            file.write(&[v]).unwrap();
-            write_imm(file, imm);
+    /// 32-bit immediate, a negative one is encoded in two's complement.
+    fn imm(&mut self) -> Result<u32, Diagnostic> {
+        let imm = self.int("an immediate", i32::MIN as i64, u32::MAX as i64)?;
+        Ok(imm as u32)
+    }
+
+    /// Name of a label or a function.
+    fn name(&mut self, what: &str) -> Result<(String, Span), Diagnostic> {
+        match self.operand(what)? {
+            (Token::Word(word), span) => Ok((word.lexeme, span)),
+            (tok, span) => Err(self.error(span, format!("expected {}, found `{}`", what, tok))),
         }
-        Inst::Ldai(imm) => {
-            write_imm(file, imm);
+    }
+
+    /// Record a use of label or function `name` by the next instruction.
+    fn fixup(&mut self, name: String, span: Span) {
+        self.fixups.push(Fixup {
+            index: self.code.len(),
+            name,
+            span,
+        });
+    }
+
+    /// Handle directive `directive`: `.function name, nregs` opens a function which starts at
+    /// the next instruction, `.end` closes it and `.line n` makes the debug info count the
+    /// following source lines from `n`.
+    fn directive(&mut self, directive: &str, span: Span) -> Result<(), Diagnostic> {
+        if directive == ".function" {
+            let (name, name_span) = self.name("a function name")?;
+            self.match_(",")?;
+            let nregs = self.int("a register count", 0, NUM_REGS as i64)?;
+
+            if self.function.is_some() {
+                let open = self.functions.last().unwrap().name.clone();
+                return Err(self.error(
+                    span,
+                    format!("function `{}` is not closed with `.end`", open),
+                ));
+            }
+            if self.functions.iter().any(|f| f.name == name) {
+                return Err(
+                    self.error(name_span, format!("function `{}` is already defined", name))
+                );
+            }
+
+            self.functions.push(FunctionInfo {
+                name,
+                entry: self.code.len() as u32,
+                nregs: nregs as u16,
+            });
+            self.function = Some(span);
+        } else if directive == ".end" {
+            if self.function.take().is_none() {
+                return Err(self.error(span, "`.end` without `.function`".to_string()));
+            }
+        } else if directive == ".line" {
+            let line = self.int("a line number", 1, u32::MAX as i64)?;
+            self.line_offset = line - (self.line as i64 + 1);
+        } else {
+            return Err(self.error(span, format!("unknown directive `{}`", directive)));
         }
+        Ok(())
+    }
 
-        Inst::Lda(v) => {
-            file.write(&[v]).unwrap();
+    /// Define label `name` at the next instruction. Local labels starting with `.L` are not
+    /// emitted to the symbol table.
+    fn label(&mut self, name: String, span: Span) -> Result<(), Diagnostic> {
+        if self.labels.contains_key(&name) {
+            return Err(self.error(span, format!("label `{}` is already defined", name)));
+        }
+        if !name.starts_with(".L") {
+            self.symbols.push(Symbol {
+                name: name.clone(),
+                index: self.code.len() as u32,
+            });
         }
-        Inst::Sta(v) => {
-            file.write(&[v]).unwrap();
+        self.labels.insert(name, self.code.len() as u32);
+        // An instruction can follow the label on the same line
+        Ok(())
+    }
+
+    /// Parse the statement starting with token `first`.
+    fn statement(&mut self, first: Token, span: Span) -> Result<(), Diagnostic> {
+        let mnem = match first {
+            Token::Word(word) => word.lexeme,
+            Token::Error(message) => return Err(self.error(span, message)),
+            _ => {
+                return Err(self.error(span, format!("expected an instruction, found `{}`", first)))
+            }
+        };
+
+        if let (Token::Token(tok), colon) = self.peek() {
+            if tok.tag == ':' as u32 && colon.line == span.line {
+                self.next();
+                return self.label(mnem, span);
+            }
+        }
+        if mnem.starts_with('.') {
+            return self.directive(&mnem, span);
+        }
+
+        let inst = if let Some(inst) = reg_inst(&mnem) {
+            inst(self.reg()?)
+        } else if let Some(inst) = imm_inst(&mnem) {
+            inst(self.imm()?)
+        } else if mnem == "mov" {
+            let v1 = self.reg()?;
+            self.match_(",")?;
+            let v2 = self.reg()?;
+
+            Inst::Mov(v1, v2)
+        } else if mnem == "movi" {
+            let vr = self.reg()?;
+            self.match_(",")?;
+            let imm = self.imm()?;
+
+            Inst::Movi(vr, imm)
+        } else if let Some(branch) = cond_branch(&mnem) {
+            let v1 = self.reg()?;
+            self.match_(",")?;
+            let v2 = self.reg()?;
+            self.match_(",")?;
+            let (label, label_span) = self.name("a label")?;
+
+            self.fixup(label, label_span);
+            branch(v1, v2, 0)
+        } else if mnem == "jmp" {
+            let (label, label_span) = self.name("a label")?;
+
+            self.fixup(label, label_span);
+            Inst::Jmp(0)
+        } else if mnem == "call" {
+            let (name, name_span) = self.name("a function name")?;
+            self.match_(",")?;
+            let argc = self.int("an argument count", 0, u8::MAX as i64)?;
+
+            self.fixup(name, name_span);
+            Inst::Call(0, argc as u8)
+        } else if mnem == "ret" {
+            Inst::Ret
+        } else if mnem == "print" {
+            Inst::Print
+        } else if mnem == "neg" {
+            Inst::Neg
+        } else if mnem == "not" {
+            Inst::Not
+        } else {
+            return Err(self.error(span, format!("unknown instruction `{}`", mnem)));
+        };
+
+        if !self.at_end_of_line() {
+            let (tok, span) = self.next();
+            return Err(self.error(span, format!("expected end of line, found `{}`", tok)));
+        }
+        self.debug.push(LineInfo {
+            index: self.code.len() as u32,
+            line: (self.line as i64 + self.line_offset) as u32,
+        });
+        self.code.push(inst);
+        Ok(())
+    }
+
+    fn fetch_insts(&mut self) {
+        loop {
+            let (tok, span) = self.next();
+            if let Token::Eof = tok {
+                break;
+            }
+            self.line = span.line;
+
+            if let Err(diagnostic) = self.statement(tok, span) {
+                self.diagnostics.push(diagnostic);
+                // Skip the rest of the statement and go on with the next line
+                while !self.at_end_of_line() {
+                    self.next();
+                }
+            }
         }
 
-        Inst::Add(v) => {
-            file.write(&[v]).unwrap();
+        if let Some(span) = self.function {
+            let name = &self.functions.last().unwrap().name;
+            let diagnostic = self.error(
+                span,
+                format!("function `{}` is not closed with `.end`", name),
+            );
+            self.diagnostics.push(diagnostic);
         }
-        Inst::Dec(v) => {
-            file.write(&[v]).unwrap();
+
+        for fixup in std::mem::take(&mut self.fixups) {
+            // Only calls refer to functions
+            let function = match self.code[fixup.index] {
+                Inst::Call(_, _) => self.functions.iter().find(|f| f.name == fixup.name),
+                _ => None,
+            };
+            let target = match function {
+                Some(function) => function.entry,
+                None => match self.labels.get(&fixup.name) {
+                    Some(label) => *label,
+                    None => {
+                        let diagnostic =
+                            self.error(fixup.span, format!("undefined label `{}`", fixup.name));
+                        self.diagnostics.push(diagnostic);
+                        continue;
+                    }
+                },
+            };
+            self.code[fixup.index].set_target(target);
         }
+    }
 
-        Inst::Bne(v1, v2, imm) => {
-            file.write(&[v1, v2]).unwrap();
-            write_imm(file, imm);
+    /// Assemble the whole source into a program starting at function `main` if there is one and
+    /// at the first instruction otherwise.
+    fn fetch_program(&mut self) -> Result<Program, Vec<Diagnostic>> {
+        self.fetch_insts();
+        if !self.diagnostics.is_empty() {
+            let mut diagnostics = std::mem::take(&mut self.diagnostics);
+            diagnostics.sort_by_key(|d| (d.span.line, d.span.column));
+            return Err(diagnostics);
         }
-        Inst::Print => (),
-    };
+
+        let entry = match self.functions.iter().find(|f| f.name == "main") {
+            Some(main) => main.entry,
+            None => 0,
+        };
+
+        Ok(Program {
+            entry,
+            code: std::mem::take(&mut self.code),
+            constants: Vec::new(),
+            symbols: std::mem::take(&mut self.symbols),
+            functions: std::mem::take(&mut self.functions),
+            debug: std::mem::take(&mut self.debug),
+        })
+    }
+}
+
+/// Assemble a source text read from `file` into a program or report all the errors in it.
+pub fn assemble(file: &str, source: &str) -> Result<Program, Vec<Diagnostic>> {
+    let lex = Lexer::new(source);
+    let mut parser = Parser::new(file, lex);
+    parser.fetch_program()
 }
 
-fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 3 {
-        println!("Lexical analyzer needs 2 arguments - source file name and output file name");
-        return ();
+#[cfg(test)]
+mod tests {
+    use crate::assembler::{assemble, Diagnostic, Span};
+    use crate::bytecode::Inst;
+    use crate::container::{FunctionInfo, LineInfo, Symbol};
+
+    #[test]
+    fn functions() {
+        let program = assemble(
+            "test.S",
+            "
+            .function main, 2
+                movi v0, 4
+                call twice, 1
+                print
+                ret
+            .end
+
+            .function twice, 1
+                lda v0
+                add v0
+                ret
+            .end
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            program.code,
+            vec![
+                Inst::Movi(0, 4),
+                Inst::Call(4, 1),
+                Inst::Print,
+                Inst::Ret,
+                Inst::Lda(0),
+                Inst::Add(0),
+                Inst::Ret,
+            ]
+        );
+        assert_eq!(program.entry, 0);
+        assert_eq!(
+            program.functions,
+            vec![
+                FunctionInfo {
+                    name: "main".to_string(),
+                    entry: 0,
+                    nregs: 2
+                },
+                FunctionInfo {
+                    name: "twice".to_string(),
+                    entry: 4,
+                    nregs: 1
+                },
+            ]
+        );
     }
-    let lex = Lexer::new(&args[1]);
-    let mut parser = Parser::new(lex);
-    let instructions = parser.fetch_insts();
 
-    let mut file = File::create(&args[2]).unwrap();
-    for inst in instructions {
-        write_inst(&mut file, inst);
+    #[test]
+    fn entry_is_main() {
+        let program = assemble(
+            "test.S",
+            "
+            .function inc, 1
+                lda v0
+                addi 1
+                ret
+            .end
+            .function main, 1
+                call inc, 0
+            .end
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(program.entry, 3);
+        assert_eq!(program.code[3], Inst::Call(0, 0));
+    }
+
+    #[test]
+    fn forward_references() {
+        let program = assemble(
+            "test.S",
+            "
+            movi v0, 1
+            beq v0, v0, Lskip
+            print
+        Lskip:
+            jmp Lend
+            call Lskip, 0
+        Lend:
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            program.code,
+            vec![
+                Inst::Movi(0, 1),
+                Inst::Beq(0, 0, 3),
+                Inst::Print,
+                Inst::Jmp(5),
+                Inst::Call(3, 0),
+            ]
+        );
+    }
+
+    /// Line, column and message of every diagnostic of `source`.
+    fn errors(source: &str) -> Vec<(u32, u32, String)> {
+        assemble("test.S", source)
+            .unwrap_err()
+            .into_iter()
+            .map(|d| (d.span.line, d.span.column, d.message))
+            .collect()
+    }
+
+    #[test]
+    fn undefined_labels() {
+        assert_eq!(
+            errors("call missing, 0\nL0:\njmp L1\nbne v0, v1, L2\njmp L0\n"),
+            vec![
+                (1, 6, "undefined label `missing`".to_string()),
+                (3, 5, "undefined label `L1`".to_string()),
+                (4, 13, "undefined label `L2`".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn recovery() {
+        assert_eq!(
+            errors(
+                "lda x1\n\
+                 movi v0 5\n\
+                 sta\n\
+                 mov v1, v300\n\
+                 print v0\n\
+                 foo\n\
+                 ldai 7\n\
+                 .end\n\
+                 .function f, 1\n"
+            ),
+            vec![
+                (1, 5, "expected a register, found `x1`".to_string()),
+                (2, 9, "expected `,`, found `5`".to_string()),
+                (3, 4, "expected a register, found end of line".to_string()),
+                (
+                    4,
+                    9,
+                    "register `v300` is out of range, the last one is v255".to_string()
+                ),
+                (5, 7, "expected end of line, found `v0`".to_string()),
+                (6, 1, "unknown instruction `foo`".to_string()),
+                (8, 1, "`.end` without `.function`".to_string()),
+                (9, 1, "function `f` is not closed with `.end`".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn syntax() {
+        let program = assemble(
+            "test.S",
+            "; comment
+            .function fib_3.2, 2 # comment
+            loop_start:
+                ldai 0x1F
+                addi 0b101
+                subi 0o17
+                muli 1_000
+                divi -1
+                movi v1, 'a'
+            .Lnext: modi '\\n'
+                movi v0, '\\''
+                bne v0, v1, loop_start
+                jmp .Lnext
+            .end
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            program.code,
+            vec![
+                Inst::Ldai(31),
+                Inst::Addi(5),
+                Inst::Subi(15),
+                Inst::Muli(1000),
+                Inst::Divi(0xffff_ffff),
+                Inst::Movi(1, 97),
+                Inst::Modi(10),
+                Inst::Movi(0, 39),
+                Inst::Bne(0, 1, 0),
+                Inst::Jmp(6),
+            ]
+        );
+        assert_eq!(program.functions[0].name, "fib_3.2");
+        // Local labels do not get to the symbol table
+        assert_eq!(
+            program.symbols,
+            vec![Symbol {
+                name: "loop_start".to_string(),
+                index: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn line_directive() {
+        let program = assemble("test.S", "print\n.line 40\nprint\n\nprint\n").unwrap();
+
+        assert_eq!(
+            program.debug,
+            vec![
+                LineInfo { index: 0, line: 1 },
+                LineInfo { index: 1, line: 40 },
+                LineInfo { index: 2, line: 42 },
+            ]
+        );
+    }
+
+    #[test]
+    fn literal_errors() {
+        assert_eq!(
+            errors(
+                "ldai 4294967296\n\
+                 ldai -2147483649\n\
+                 ldai 99999999999999999999\n\
+                 ldai 5.\n\
+                 ldai 0x\n\
+                 ldai 0b102\n\
+                 call f, 256\n\
+                 ldai '\\q'\n\
+                 ldai 'ab'\n\
+                 .function f, 257\n\
+                 L: L:\n"
+            ),
+            vec![
+                (
+                    1,
+                    6,
+                    "an immediate `4294967296` is out of range, expected -2147483648 to 4294967295"
+                        .to_string()
+                ),
+                (
+                    2,
+                    6,
+                    "an immediate `-2147483649` is out of range, expected -2147483648 to 4294967295"
+                        .to_string()
+                ),
+                (
+                    3,
+                    6,
+                    "integer literal `99999999999999999999` is too large".to_string()
+                ),
+                (4, 6, "invalid decimal literal `5.`".to_string()),
+                (5, 6, "invalid hexadecimal literal `0x`".to_string()),
+                (6, 6, "invalid binary literal `0b102`".to_string()),
+                (
+                    7,
+                    9,
+                    "an argument count `256` is out of range, expected 0 to 255".to_string()
+                ),
+                (8, 6, "unknown character escape `\\q`".to_string()),
+                (9, 6, "unterminated character literal".to_string()),
+                (
+                    10,
+                    14,
+                    "a register count `257` is out of range, expected 0 to 256".to_string()
+                ),
+                (11, 4, "label `L` is already defined".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn render() {
+        let source = "movi v0, 1\n\tbne v0, v1, Lloop\n";
+        let diagnostics = assemble("loop.S", source).unwrap_err();
+
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic {
+                file: "loop.S".to_string(),
+                span: Span {
+                    line: 2,
+                    column: 14,
+                    len: 5
+                },
+                message: "undefined label `Lloop`".to_string(),
+            }]
+        );
+        assert_eq!(
+            diagnostics[0].render(source),
+            "error: undefined label `Lloop`\n \
+             --> loop.S:2:14\n  \
+             |\n\
+             2 | \tbne v0, v1, Lloop\n  \
+             | \t            ^^^^^"
+        );
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "loop.S:2:14: undefined label `Lloop`"
+        );
     }
 }
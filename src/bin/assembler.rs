@@ -0,0 +1,36 @@
+use vm::assembler::assemble;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        println!("Lexical analyzer needs 2 arguments - source file name and output file name");
+        return;
+    }
+    let source = match std::fs::read_to_string(&args[1]) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("{}: {}", args[1], e);
+            std::process::exit(1);
+        }
+    };
+
+    let program = match assemble(&args[1], &source) {
+        Ok(program) => program,
+        Err(diagnostics) => {
+            for diagnostic in &diagnostics {
+                eprintln!("{}\n", diagnostic.render(&source));
+            }
+            eprintln!(
+                "error: aborting due to {} previous error{}",
+                diagnostics.len(),
+                if diagnostics.len() == 1 { "" } else { "s" }
+            );
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(e) = std::fs::write(&args[2], program.to_bytes()) {
+        eprintln!("{}: {}", args[2], e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,27 @@
+use vm::container::Program;
+use vm::disassembler::disassemble;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 2 {
+        println!("Disassembler needs a bytecode file name");
+        return;
+    }
+
+    let bytes = match std::fs::read(&args[1]) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("{}: {}", args[1], e);
+            std::process::exit(1);
+        }
+    };
+    let program = match Program::from_bytes(&bytes) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("{}: {}", args[1], e);
+            std::process::exit(1);
+        }
+    };
+
+    print!("{}", disassemble(&program));
+}
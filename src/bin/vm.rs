@@ -0,0 +1,107 @@
+use vm::container::Program;
+use vm::jit::cfg::build_cfg;
+use vm::jit::dot::{cfg_to_dot, function_to_dot};
+use vm::jit::lower::lower;
+use vm::verifier::verify;
+use vm::vm::{Vm, JIT_THRESHOLD};
+
+fn main() {
+    let (options, args): (Vec<String>, Vec<String>) =
+        std::env::args().partition(|arg| arg.starts_with("--"));
+    if args.len() != 2 && args.len() != 3 {
+        println!(
+            "Virtual machine needs a bytecode file name and optionally an entry function name"
+        );
+        println!("Options:");
+        println!(
+            "    --dump-cfg=dot     print the control-flow graphs in DOT format instead of running"
+        );
+        println!("    --jit              compile hot functions and loops to machine code");
+        println!("    --jit-threshold=N  --jit compiling code after N entries or loop iterations,");
+        println!("                       {} by default", JIT_THRESHOLD);
+        return;
+    }
+    let mut dump_cfg = false;
+    let mut jit: Option<u32> = None;
+    for option in &options {
+        match option.as_str() {
+            "--dump-cfg=dot" => dump_cfg = true,
+            "--jit" => jit = jit.or(Some(JIT_THRESHOLD)),
+            _ if option.starts_with("--jit-threshold=") => {
+                match option["--jit-threshold=".len()..].parse() {
+                    Ok(threshold) => jit = Some(threshold),
+                    Err(_) => {
+                        eprintln!("invalid option {}", option);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            _ => {
+                eprintln!("unknown option {}", option);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let bytes = match std::fs::read(&args[1]) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("{}: {}", args[1], e);
+            std::process::exit(1);
+        }
+    };
+    let program = match Program::from_bytes(&bytes) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("{}: {}", args[1], e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(errors) = verify(&program) {
+        for e in errors {
+            match program.line(e.pc()) {
+                Some(line) => eprintln!("{}: line {}: {}", args[1], line, e),
+                None => eprintln!("{}: {}", args[1], e),
+            }
+        }
+        std::process::exit(1);
+    }
+
+    let mut vm = Vm::new();
+    vm.load(program);
+    if args.len() == 3 && !vm.enter(&args[2]) {
+        eprintln!("{}: no function {}", args[1], args[2]);
+        std::process::exit(1);
+    }
+    if dump_cfg {
+        // The bytecode graph of the whole program and the SSA graph of the code reachable from
+        // the entry point
+        let code = &vm.program().code;
+        print!("{}", cfg_to_dot(code, &build_cfg(code)));
+        if vm.pc() < code.len() {
+            print!("{}", function_to_dot(&lower(code, vm.pc())));
+        }
+        return;
+    }
+    if let Some(threshold) = jit {
+        enable_jit(&mut vm, threshold, &args[1]);
+    }
+    if let Err(e) = vm.run() {
+        eprintln!("{}: {}", args[1], e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn enable_jit<W: std::io::Write>(vm: &mut Vm<W>, threshold: u32, _file: &str) {
+    vm.set_jit_threshold(Some(threshold));
+}
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+fn enable_jit<W: std::io::Write>(_vm: &mut Vm<W>, _threshold: u32, file: &str) {
+    eprintln!(
+        "{}: the JIT does not support this platform, interpreting",
+        file
+    );
+}
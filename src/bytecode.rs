@@ -1,8 +1,9 @@
 use std::convert::TryInto;
+use std::fmt;
 
 type Reg = u8;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Inst {
     Mov(Reg, Reg),
     Movi(Reg, u32),
@@ -13,15 +14,202 @@ pub enum Inst {
     Dec(Reg),
     Bne(Reg, Reg, u32),
     Print,
+    Sub(Reg),
+    Mul(Reg),
+    Div(Reg),
+    Mod(Reg),
+    Neg,
+    Addi(u32),
+    Subi(u32),
+    Muli(u32),
+    Divi(u32),
+    Modi(u32),
+    And(Reg),
+    Or(Reg),
+    Xor(Reg),
+    Not,
+    Shl(Reg),
+    Shr(Reg),
+    Sar(Reg),
+    Jmp(u32),
+    Beq(Reg, Reg, u32),
+    Blt(Reg, Reg, u32),
+    Ble(Reg, Reg, u32),
+    Bgt(Reg, Reg, u32),
+    Bge(Reg, Reg, u32),
+    Bltu(Reg, Reg, u32),
+    Bleu(Reg, Reg, u32),
+    Bgtu(Reg, Reg, u32),
+    Bgeu(Reg, Reg, u32),
+    Call(u32, u8),
+    Ret,
 }
 
 impl Inst {
     pub fn is_branch(&self) -> bool {
+        self.branch_target().is_some()
+    }
+
+    /// Index of the instruction a branch jumps to.
+    pub fn branch_target(&self) -> Option<u32> {
+        match self {
+            Self::Jmp(imm)
+            | Self::Bne(_, _, imm)
+            | Self::Beq(_, _, imm)
+            | Self::Blt(_, _, imm)
+            | Self::Ble(_, _, imm)
+            | Self::Bgt(_, _, imm)
+            | Self::Bge(_, _, imm)
+            | Self::Bltu(_, _, imm)
+            | Self::Bleu(_, _, imm)
+            | Self::Bgtu(_, _, imm)
+            | Self::Bgeu(_, _, imm) => Some(*imm),
+            _ => None,
+        }
+    }
+
+    /// Replace the target of a branch or a call.
+    pub fn set_target(&mut self, target: u32) {
+        match self {
+            Self::Jmp(imm)
+            | Self::Bne(_, _, imm)
+            | Self::Beq(_, _, imm)
+            | Self::Blt(_, _, imm)
+            | Self::Ble(_, _, imm)
+            | Self::Bgt(_, _, imm)
+            | Self::Bge(_, _, imm)
+            | Self::Bltu(_, _, imm)
+            | Self::Bleu(_, _, imm)
+            | Self::Bgtu(_, _, imm)
+            | Self::Bgeu(_, _, imm)
+            | Self::Call(imm, _) => *imm = target,
+            _ => panic!("{:?} has no target", self),
+        }
+    }
+
+    /// Assembly mnemonic of the instruction.
+    pub fn mnemonic(&self) -> &'static str {
         match self {
-            Self::Bne { .. } => true,
-            _ => false,
+            Self::Mov(..) => "mov",
+            Self::Movi(..) => "movi",
+            Self::Ldai(_) => "ldai",
+            Self::Lda(_) => "lda",
+            Self::Sta(_) => "sta",
+            Self::Add(_) => "add",
+            Self::Dec(_) => "dec",
+            Self::Bne(..) => "bne",
+            Self::Print => "print",
+            Self::Sub(_) => "sub",
+            Self::Mul(_) => "mul",
+            Self::Div(_) => "div",
+            Self::Mod(_) => "mod",
+            Self::Neg => "neg",
+            Self::Addi(_) => "addi",
+            Self::Subi(_) => "subi",
+            Self::Muli(_) => "muli",
+            Self::Divi(_) => "divi",
+            Self::Modi(_) => "modi",
+            Self::And(_) => "and",
+            Self::Or(_) => "or",
+            Self::Xor(_) => "xor",
+            Self::Not => "not",
+            Self::Shl(_) => "shl",
+            Self::Shr(_) => "shr",
+            Self::Sar(_) => "sar",
+            Self::Jmp(_) => "jmp",
+            Self::Beq(..) => "beq",
+            Self::Blt(..) => "blt",
+            Self::Ble(..) => "ble",
+            Self::Bgt(..) => "bgt",
+            Self::Bge(..) => "bge",
+            Self::Bltu(..) => "bltu",
+            Self::Bleu(..) => "bleu",
+            Self::Bgtu(..) => "bgtu",
+            Self::Bgeu(..) => "bgeu",
+            Self::Call(..) => "call",
+            Self::Ret => "ret",
         }
     }
+
+    /// Registers the instruction reads, a call reads its arguments.
+    pub fn reg_uses(&self) -> Vec<Reg> {
+        match *self {
+            Self::Mov(_, v)
+            | Self::Lda(v)
+            | Self::Add(v)
+            | Self::Dec(v)
+            | Self::Sub(v)
+            | Self::Mul(v)
+            | Self::Div(v)
+            | Self::Mod(v)
+            | Self::And(v)
+            | Self::Or(v)
+            | Self::Xor(v)
+            | Self::Shl(v)
+            | Self::Shr(v)
+            | Self::Sar(v) => vec![v],
+            Self::Bne(v1, v2, _)
+            | Self::Beq(v1, v2, _)
+            | Self::Blt(v1, v2, _)
+            | Self::Ble(v1, v2, _)
+            | Self::Bgt(v1, v2, _)
+            | Self::Bge(v1, v2, _)
+            | Self::Bltu(v1, v2, _)
+            | Self::Bleu(v1, v2, _)
+            | Self::Bgtu(v1, v2, _)
+            | Self::Bgeu(v1, v2, _) => vec![v1, v2],
+            Self::Call(_, argc) => (0..argc).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Register the instruction writes.
+    pub fn reg_def(&self) -> Option<Reg> {
+        match *self {
+            Self::Mov(v, _) | Self::Movi(v, _) | Self::Sta(v) | Self::Dec(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Does the instruction read the accumulator? `ret` leaves it to the caller as it is without
+    /// reading it, functions without a result do not have to write it.
+    pub fn reads_acc(&self) -> bool {
+        !matches!(
+            self,
+            Self::Mov(..)
+                | Self::Movi(..)
+                | Self::Ldai(_)
+                | Self::Lda(_)
+                | Self::Dec(_)
+                | Self::Jmp(_)
+                | Self::Bne(..)
+                | Self::Beq(..)
+                | Self::Blt(..)
+                | Self::Ble(..)
+                | Self::Bgt(..)
+                | Self::Bge(..)
+                | Self::Bltu(..)
+                | Self::Bleu(..)
+                | Self::Bgtu(..)
+                | Self::Bgeu(..)
+                | Self::Call(..)
+                | Self::Ret
+        )
+    }
+
+    /// Does the instruction write the accumulator? A call leaves the result of the callee in it.
+    pub fn writes_acc(&self) -> bool {
+        !matches!(
+            self,
+            Self::Mov(..) | Self::Movi(..) | Self::Sta(_) | Self::Dec(_) | Self::Print | Self::Ret
+        ) && !self.is_branch()
+    }
+
+    /// Can the instruction following this one be executed right after it? A call continues at
+    /// the following instruction once the callee returns.
+    pub fn falls_through(&self) -> bool {
+        !matches!(self, Self::Jmp(_) | Self::Ret)
+    }
 }
 
 impl TryInto<u8> for Inst {
@@ -38,6 +226,341 @@ impl TryInto<u8> for Inst {
             Inst::Dec(_) => Ok(6),
             Inst::Bne(_, _, _) => Ok(7),
             Inst::Print => Ok(8),
+            Inst::Sub(_) => Ok(9),
+            Inst::Mul(_) => Ok(10),
+            Inst::Div(_) => Ok(11),
+            Inst::Mod(_) => Ok(12),
+            Inst::Neg => Ok(13),
+            Inst::Addi(_) => Ok(14),
+            Inst::Subi(_) => Ok(15),
+            Inst::Muli(_) => Ok(16),
+            Inst::Divi(_) => Ok(17),
+            Inst::Modi(_) => Ok(18),
+            Inst::And(_) => Ok(19),
+            Inst::Or(_) => Ok(20),
+            Inst::Xor(_) => Ok(21),
+            Inst::Not => Ok(22),
+            Inst::Shl(_) => Ok(23),
+            Inst::Shr(_) => Ok(24),
+            Inst::Sar(_) => Ok(25),
+            Inst::Jmp(_) => Ok(26),
+            Inst::Beq(_, _, _) => Ok(27),
+            Inst::Blt(_, _, _) => Ok(28),
+            Inst::Ble(_, _, _) => Ok(29),
+            Inst::Bgt(_, _, _) => Ok(30),
+            Inst::Bge(_, _, _) => Ok(31),
+            Inst::Bltu(_, _, _) => Ok(32),
+            Inst::Bleu(_, _, _) => Ok(33),
+            Inst::Bgtu(_, _, _) => Ok(34),
+            Inst::Bgeu(_, _, _) => Ok(35),
+            Inst::Call(_, _) => Ok(36),
+            Inst::Ret => Ok(37),
+        }
+    }
+}
+
+/// Reasons a byte stream cannot be decoded into instructions.
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    /// The stream ended inside the operands of the instruction `opcode`; `offset` is the first
+    /// missing byte.
+    TruncatedOperand { opcode: u8, offset: usize },
+    /// The byte at `offset` is not an opcode of any instruction.
+    UnknownOpcode { opcode: u8, offset: usize },
+}
+
+impl DecodeError {
+    /// Byte offset at which decoding failed.
+    pub fn offset(&self) -> usize {
+        match self {
+            Self::TruncatedOperand { offset, .. } => *offset,
+            Self::UnknownOpcode { offset, .. } => *offset,
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::TruncatedOperand { opcode, offset } => write!(
+                f,
+                "truncated operand of opcode {} at byte offset {}",
+                opcode, offset
+            ),
+            Self::UnknownOpcode { opcode, offset } => {
+                write!(f, "unknown opcode {} at byte offset {}", opcode, offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Append the binary form of `inst` to `out`: the opcode byte followed by the operands, registers
+/// as single bytes and immediates as little-endian `u32`.
+pub fn encode(inst: Inst, out: &mut Vec<u8>) {
+    out.push(TryInto::<u8>::try_into(inst).unwrap());
+
+    match inst {
+        Inst::Mov(v1, v2) => out.extend_from_slice(&[v1, v2]),
+        Inst::Movi(v, imm) => {
+            out.push(v);
+            out.extend_from_slice(&imm.to_le_bytes());
+        }
+        Inst::Ldai(imm)
+        | Inst::Addi(imm)
+        | Inst::Subi(imm)
+        | Inst::Muli(imm)
+        | Inst::Divi(imm)
+        | Inst::Modi(imm)
+        | Inst::Jmp(imm) => out.extend_from_slice(&imm.to_le_bytes()),
+        Inst::Lda(v)
+        | Inst::Sta(v)
+        | Inst::Add(v)
+        | Inst::Dec(v)
+        | Inst::Sub(v)
+        | Inst::Mul(v)
+        | Inst::Div(v)
+        | Inst::Mod(v)
+        | Inst::And(v)
+        | Inst::Or(v)
+        | Inst::Xor(v)
+        | Inst::Shl(v)
+        | Inst::Shr(v)
+        | Inst::Sar(v) => out.push(v),
+        Inst::Bne(v1, v2, imm)
+        | Inst::Beq(v1, v2, imm)
+        | Inst::Blt(v1, v2, imm)
+        | Inst::Ble(v1, v2, imm)
+        | Inst::Bgt(v1, v2, imm)
+        | Inst::Bge(v1, v2, imm)
+        | Inst::Bltu(v1, v2, imm)
+        | Inst::Bleu(v1, v2, imm)
+        | Inst::Bgtu(v1, v2, imm)
+        | Inst::Bgeu(v1, v2, imm) => {
+            out.extend_from_slice(&[v1, v2]);
+            out.extend_from_slice(&imm.to_le_bytes());
+        }
+        Inst::Call(imm, argc) => {
+            out.extend_from_slice(&imm.to_le_bytes());
+            out.push(argc);
+        }
+        Inst::Print | Inst::Neg | Inst::Not | Inst::Ret => (),
+    }
+}
+
+/// Operand reader used by `decode`, remembers the opcode for error reports.
+struct Operands<'a> {
+    bytes: &'a [u8],
+    opcode: u8,
+    offset: usize,
+}
+
+impl<'a> Operands<'a> {
+    fn reg(&mut self) -> Result<Reg, DecodeError> {
+        match self.bytes.get(self.offset) {
+            Some(byte) => {
+                self.offset += 1;
+                Ok(*byte)
+            }
+            None => Err(DecodeError::TruncatedOperand {
+                opcode: self.opcode,
+                offset: self.bytes.len(),
+            }),
+        }
+    }
+
+    fn imm(&mut self) -> Result<u32, DecodeError> {
+        let end = self.offset + 4;
+        if end > self.bytes.len() {
+            return Err(DecodeError::TruncatedOperand {
+                opcode: self.opcode,
+                offset: self.bytes.len(),
+            });
         }
+        let imm = u32::from_le_bytes(self.bytes[self.offset..end].try_into().unwrap());
+        self.offset = end;
+        Ok(imm)
+    }
+}
+
+/// Decode the instruction starting at `offset`, which must be within `bytes`. Returns the
+/// instruction and the offset of the one following it.
+pub fn decode(bytes: &[u8], offset: usize) -> Result<(Inst, usize), DecodeError> {
+    let opcode = bytes[offset];
+    let mut ops = Operands {
+        bytes,
+        opcode,
+        offset: offset + 1,
+    };
+
+    let inst = match opcode {
+        0 => Inst::Mov(ops.reg()?, ops.reg()?),
+        1 => Inst::Movi(ops.reg()?, ops.imm()?),
+        2 => Inst::Ldai(ops.imm()?),
+        3 => Inst::Lda(ops.reg()?),
+        4 => Inst::Sta(ops.reg()?),
+        5 => Inst::Add(ops.reg()?),
+        6 => Inst::Dec(ops.reg()?),
+        7 => Inst::Bne(ops.reg()?, ops.reg()?, ops.imm()?),
+        8 => Inst::Print,
+        9 => Inst::Sub(ops.reg()?),
+        10 => Inst::Mul(ops.reg()?),
+        11 => Inst::Div(ops.reg()?),
+        12 => Inst::Mod(ops.reg()?),
+        13 => Inst::Neg,
+        14 => Inst::Addi(ops.imm()?),
+        15 => Inst::Subi(ops.imm()?),
+        16 => Inst::Muli(ops.imm()?),
+        17 => Inst::Divi(ops.imm()?),
+        18 => Inst::Modi(ops.imm()?),
+        19 => Inst::And(ops.reg()?),
+        20 => Inst::Or(ops.reg()?),
+        21 => Inst::Xor(ops.reg()?),
+        22 => Inst::Not,
+        23 => Inst::Shl(ops.reg()?),
+        24 => Inst::Shr(ops.reg()?),
+        25 => Inst::Sar(ops.reg()?),
+        26 => Inst::Jmp(ops.imm()?),
+        27 => Inst::Beq(ops.reg()?, ops.reg()?, ops.imm()?),
+        28 => Inst::Blt(ops.reg()?, ops.reg()?, ops.imm()?),
+        29 => Inst::Ble(ops.reg()?, ops.reg()?, ops.imm()?),
+        30 => Inst::Bgt(ops.reg()?, ops.reg()?, ops.imm()?),
+        31 => Inst::Bge(ops.reg()?, ops.reg()?, ops.imm()?),
+        32 => Inst::Bltu(ops.reg()?, ops.reg()?, ops.imm()?),
+        33 => Inst::Bleu(ops.reg()?, ops.reg()?, ops.imm()?),
+        34 => Inst::Bgtu(ops.reg()?, ops.reg()?, ops.imm()?),
+        35 => Inst::Bgeu(ops.reg()?, ops.reg()?, ops.imm()?),
+        36 => Inst::Call(ops.imm()?, ops.reg()?),
+        37 => Inst::Ret,
+        _ => return Err(DecodeError::UnknownOpcode { opcode, offset }),
+    };
+
+    Ok((inst, ops.offset))
+}
+
+/// Decode a whole instruction stream.
+pub fn decode_all(bytes: &[u8]) -> Result<Vec<Inst>, DecodeError> {
+    let mut ret = Vec::new();
+    let mut offset = 0;
+
+    while offset < bytes.len() {
+        let (inst, next) = decode(bytes, offset)?;
+        ret.push(inst);
+        offset = next;
+    }
+
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::{decode, decode_all, encode, DecodeError, Inst};
+
+    fn all_insts() -> Vec<Inst> {
+        vec![
+            Inst::Mov(1, 255),
+            Inst::Movi(7, 0xdead_beef),
+            Inst::Ldai(u32::MAX),
+            Inst::Lda(3),
+            Inst::Sta(4),
+            Inst::Add(5),
+            Inst::Dec(6),
+            Inst::Bne(8, 9, 0x0102_0304),
+            Inst::Print,
+            Inst::Sub(10),
+            Inst::Mul(11),
+            Inst::Div(12),
+            Inst::Mod(13),
+            Inst::Neg,
+            Inst::Addi(14),
+            Inst::Subi(15),
+            Inst::Muli(16),
+            Inst::Divi(17),
+            Inst::Modi(0x8000_0000),
+            Inst::And(19),
+            Inst::Or(20),
+            Inst::Xor(21),
+            Inst::Not,
+            Inst::Shl(23),
+            Inst::Shr(24),
+            Inst::Sar(25),
+            Inst::Jmp(26),
+            Inst::Beq(1, 2, 27),
+            Inst::Blt(3, 4, 28),
+            Inst::Ble(5, 6, 29),
+            Inst::Bgt(7, 8, 30),
+            Inst::Bge(9, 10, 31),
+            Inst::Bltu(11, 12, 32),
+            Inst::Bleu(13, 14, 33),
+            Inst::Bgtu(15, 16, 34),
+            Inst::Bgeu(17, 18, 35),
+            Inst::Call(36, 255),
+            Inst::Ret,
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        for inst in all_insts() {
+            let mut bytes = Vec::new();
+            encode(inst, &mut bytes);
+            assert_eq!(decode(&bytes, 0), Ok((inst, bytes.len())));
+        }
+
+        let mut bytes = Vec::new();
+        for inst in all_insts() {
+            encode(inst, &mut bytes);
+        }
+        assert_eq!(decode_all(&bytes), Ok(all_insts()));
+    }
+
+    #[test]
+    fn truncated_operand() {
+        let mut bytes = Vec::new();
+        encode(Inst::Print, &mut bytes);
+        encode(Inst::Bne(1, 2, 3), &mut bytes);
+        bytes.truncate(5);
+
+        assert_eq!(
+            decode_all(&bytes),
+            Err(DecodeError::TruncatedOperand {
+                opcode: 7,
+                offset: 5
+            })
+        );
+    }
+
+    #[test]
+    fn unknown_opcode() {
+        let bytes = [8, 8, 200];
+        let err = decode_all(&bytes).unwrap_err();
+
+        assert_eq!(
+            err,
+            DecodeError::UnknownOpcode {
+                opcode: 200,
+                offset: 2
+            }
+        );
+        assert_eq!(err.offset(), 2);
+    }
+
+    #[test]
+    fn uses_and_defs() {
+        assert_eq!(Inst::Mov(1, 2).reg_uses(), vec![2]);
+        assert_eq!(Inst::Mov(1, 2).reg_def(), Some(1));
+        assert_eq!(Inst::Dec(3).reg_uses(), vec![3]);
+        assert_eq!(Inst::Dec(3).reg_def(), Some(3));
+        assert_eq!(Inst::Bltu(4, 5, 0).reg_uses(), vec![4, 5]);
+        assert_eq!(Inst::Call(0, 3).reg_uses(), vec![0, 1, 2]);
+        assert_eq!(Inst::Sta(6).reg_def(), Some(6));
+
+        assert!(Inst::Sta(0).reads_acc() && !Inst::Sta(0).writes_acc());
+        assert!(Inst::Add(0).reads_acc() && Inst::Add(0).writes_acc());
+        assert!(!Inst::Ldai(0).reads_acc() && Inst::Ldai(0).writes_acc());
+        assert!(!Inst::Call(0, 0).reads_acc() && Inst::Call(0, 0).writes_acc());
+        assert!(!Inst::Ret.reads_acc() && !Inst::Ret.writes_acc());
+        assert!(!Inst::Jmp(0).reads_acc() && !Inst::Jmp(0).writes_acc());
     }
 }
@@ -0,0 +1,432 @@
+//! Container format of the files produced by the assembler.
+//!
+//! ```text
+//! magic         "VMBC"
+//! version       major: u16, minor: u16
+//! entry         u32, index of the first instruction to execute
+//! section count u16
+//! section table section count * (kind: u8, offset: u32, size: u32)
+//! sections      payloads addressed by the table, offsets are from the start of the file
+//! ```
+//!
+//! All integers are little-endian. A reader accepts any file with its own major version and
+//! skips sections of unknown kinds, so new sections only need a minor version bump.
+
+use std::convert::TryInto;
+use std::fmt;
+
+use crate::bytecode::{self, DecodeError, Inst};
+
+pub const MAGIC: [u8; 4] = *b"VMBC";
+pub const VERSION_MAJOR: u16 = 1;
+pub const VERSION_MINOR: u16 = 1;
+
+const HEADER_SIZE: usize = 14;
+const SECTION_ENTRY_SIZE: usize = 9;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SectionKind {
+    Code = 1,
+    Constants = 2,
+    Symbols = 3,
+    Debug = 4,
+    /// Since version 1.1
+    Functions = 5,
+}
+
+impl SectionKind {
+    fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            1 => Some(Self::Code),
+            2 => Some(Self::Constants),
+            3 => Some(Self::Symbols),
+            4 => Some(Self::Debug),
+            5 => Some(Self::Functions),
+            _ => None,
+        }
+    }
+}
+
+/// Named instruction index, e.g. a label of the assembly source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub index: u32,
+}
+
+/// Entry point and frame size of a function.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionInfo {
+    pub name: String,
+    pub entry: u32,
+    /// Number of registers in the frames of the function.
+    pub nregs: u16,
+}
+
+/// Source line an instruction was assembled from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LineInfo {
+    pub index: u32,
+    pub line: u32,
+}
+
+/// Contents of a bytecode file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Program {
+    pub entry: u32,
+    pub code: Vec<Inst>,
+    pub constants: Vec<u64>,
+    pub symbols: Vec<Symbol>,
+    pub functions: Vec<FunctionInfo>,
+    pub debug: Vec<LineInfo>,
+}
+
+/// Reasons a file cannot be read as a `Program`.
+#[derive(Debug, PartialEq)]
+pub enum ReadError {
+    /// The file does not start with `MAGIC`.
+    BadMagic,
+    /// The file was written by an assembler with an incompatible format version.
+    UnsupportedVersion { major: u16, minor: u16 },
+    /// The file ended at `offset` while reading the header or a section.
+    Truncated { offset: usize },
+    /// A section of this kind is mandatory but is absent.
+    MissingSection(u8),
+    /// The code section does not decode, offsets are relative to the section.
+    Code(DecodeError),
+    /// A symbol or function name is not valid UTF-8.
+    InvalidSymbol { offset: usize },
+    /// The entry point is past the end of the code.
+    InvalidEntry(u32),
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "not a bytecode file"),
+            Self::UnsupportedVersion { major, minor } => write!(
+                f,
+                "unsupported format version {}.{}, expected {}.x",
+                major, minor, VERSION_MAJOR
+            ),
+            Self::Truncated { offset } => write!(f, "file truncated at byte offset {}", offset),
+            Self::MissingSection(kind) => write!(f, "missing section of kind {}", kind),
+            Self::Code(e) => write!(f, "code section: {}", e),
+            Self::InvalidSymbol { offset } => {
+                write!(f, "name at byte offset {} is not UTF-8", offset)
+            }
+            Self::InvalidEntry(entry) => write!(f, "entry point {} is out of code", entry),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+/// Cursor over the bytes of a file, reports truncation with the absolute offset.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, size: usize) -> Result<&'a [u8], ReadError> {
+        let end = self.offset + size;
+        if end > self.bytes.len() {
+            return Err(ReadError::Truncated {
+                offset: self.bytes.len(),
+            });
+        }
+        let ret = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(ret)
+    }
+
+    fn u8(&mut self) -> Result<u8, ReadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ReadError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, ReadError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, ReadError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Name prefixed by its `u16` length.
+    fn name(&mut self) -> Result<String, ReadError> {
+        let len = self.u16()? as usize;
+        let offset = self.offset;
+        match std::str::from_utf8(self.take(len)?) {
+            Ok(name) => Ok(name.to_string()),
+            Err(_) => Err(ReadError::InvalidSymbol { offset }),
+        }
+    }
+}
+
+impl Program {
+    /// Source line of the instruction at `index` according to the debug info.
+    pub fn line(&self, index: usize) -> Option<u32> {
+        self.debug
+            .iter()
+            .find(|info| info.index as usize == index)
+            .map(|info| info.line)
+    }
+
+    /// Serialize the program into the container format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut code = Vec::new();
+        for inst in &self.code {
+            bytecode::encode(*inst, &mut code);
+        }
+
+        let mut constants = Vec::new();
+        constants.extend_from_slice(&(self.constants.len() as u32).to_le_bytes());
+        for constant in &self.constants {
+            constants.extend_from_slice(&constant.to_le_bytes());
+        }
+
+        let mut symbols = Vec::new();
+        symbols.extend_from_slice(&(self.symbols.len() as u32).to_le_bytes());
+        for symbol in &self.symbols {
+            symbols.extend_from_slice(&(symbol.name.len() as u16).to_le_bytes());
+            symbols.extend_from_slice(symbol.name.as_bytes());
+            symbols.extend_from_slice(&symbol.index.to_le_bytes());
+        }
+
+        let mut functions = Vec::new();
+        functions.extend_from_slice(&(self.functions.len() as u32).to_le_bytes());
+        for function in &self.functions {
+            functions.extend_from_slice(&(function.name.len() as u16).to_le_bytes());
+            functions.extend_from_slice(function.name.as_bytes());
+            functions.extend_from_slice(&function.entry.to_le_bytes());
+            functions.extend_from_slice(&function.nregs.to_le_bytes());
+        }
+
+        let mut debug = Vec::new();
+        debug.extend_from_slice(&(self.debug.len() as u32).to_le_bytes());
+        for info in &self.debug {
+            debug.extend_from_slice(&info.index.to_le_bytes());
+            debug.extend_from_slice(&info.line.to_le_bytes());
+        }
+
+        let sections = [
+            (SectionKind::Code, code),
+            (SectionKind::Constants, constants),
+            (SectionKind::Symbols, symbols),
+            (SectionKind::Debug, debug),
+            (SectionKind::Functions, functions),
+        ];
+
+        let mut ret = Vec::new();
+        ret.extend_from_slice(&MAGIC);
+        ret.extend_from_slice(&VERSION_MAJOR.to_le_bytes());
+        ret.extend_from_slice(&VERSION_MINOR.to_le_bytes());
+        ret.extend_from_slice(&self.entry.to_le_bytes());
+        ret.extend_from_slice(&(sections.len() as u16).to_le_bytes());
+
+        let mut offset = HEADER_SIZE + sections.len() * SECTION_ENTRY_SIZE;
+        for (kind, payload) in &sections {
+            ret.push(*kind as u8);
+            ret.extend_from_slice(&(offset as u32).to_le_bytes());
+            ret.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            offset += payload.len();
+        }
+        for (_, payload) in &sections {
+            ret.extend_from_slice(payload);
+        }
+
+        ret
+    }
+
+    /// Parse a file in the container format.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, ReadError> {
+        let mut reader = Reader { bytes, offset: 0 };
+
+        if reader.take(MAGIC.len()).map_err(|_| ReadError::BadMagic)? != MAGIC {
+            return Err(ReadError::BadMagic);
+        }
+        let major = reader.u16()?;
+        let minor = reader.u16()?;
+        if major != VERSION_MAJOR {
+            return Err(ReadError::UnsupportedVersion { major, minor });
+        }
+
+        let mut ret = Program {
+            entry: reader.u32()?,
+            ..Default::default()
+        };
+        let mut has_code = false;
+
+        let count = reader.u16()?;
+        for _ in 0..count {
+            let kind = reader.u8()?;
+            let offset = reader.u32()? as usize;
+            let size = reader.u32()? as usize;
+            if offset + size > bytes.len() {
+                return Err(ReadError::Truncated {
+                    offset: bytes.len(),
+                });
+            }
+            let mut section = Reader {
+                bytes: &bytes[..offset + size],
+                offset,
+            };
+
+            match SectionKind::from_u8(kind) {
+                Some(SectionKind::Code) => {
+                    ret.code =
+                        bytecode::decode_all(section.take(size)?).map_err(ReadError::Code)?;
+                    has_code = true;
+                }
+                Some(SectionKind::Constants) => {
+                    for _ in 0..section.u32()? {
+                        ret.constants.push(section.u64()?);
+                    }
+                }
+                Some(SectionKind::Symbols) => {
+                    for _ in 0..section.u32()? {
+                        ret.symbols.push(Symbol {
+                            name: section.name()?,
+                            index: section.u32()?,
+                        });
+                    }
+                }
+                Some(SectionKind::Debug) => {
+                    for _ in 0..section.u32()? {
+                        ret.debug.push(LineInfo {
+                            index: section.u32()?,
+                            line: section.u32()?,
+                        });
+                    }
+                }
+                Some(SectionKind::Functions) => {
+                    for _ in 0..section.u32()? {
+                        ret.functions.push(FunctionInfo {
+                            name: section.name()?,
+                            entry: section.u32()?,
+                            nregs: section.u16()?,
+                        });
+                    }
+                }
+                // Sections introduced by newer minor versions
+                None => (),
+            }
+        }
+
+        if !has_code {
+            return Err(ReadError::MissingSection(SectionKind::Code as u8));
+        }
+        if ret.entry as usize > ret.code.len() {
+            return Err(ReadError::InvalidEntry(ret.entry));
+        }
+
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::{DecodeError, Inst};
+    use crate::container::{
+        FunctionInfo, LineInfo, Program, ReadError, Symbol, HEADER_SIZE, MAGIC, SECTION_ENTRY_SIZE,
+        VERSION_MAJOR, VERSION_MINOR,
+    };
+
+    fn program() -> Program {
+        Program {
+            entry: 1,
+            code: vec![Inst::Movi(0, 3), Inst::Dec(0), Inst::Bne(0, 1, 1)],
+            constants: vec![u64::MAX, 7],
+            symbols: vec![Symbol {
+                name: "L1".to_string(),
+                index: 1,
+            }],
+            functions: vec![FunctionInfo {
+                name: "f".to_string(),
+                entry: 0,
+                nregs: 2,
+            }],
+            debug: vec![
+                LineInfo { index: 0, line: 1 },
+                LineInfo { index: 1, line: 3 },
+            ],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = program().to_bytes();
+
+        assert_eq!(&bytes[..4], &MAGIC);
+        assert_eq!(Program::from_bytes(&bytes), Ok(program()));
+    }
+
+    #[test]
+    fn rejects_foreign_files() {
+        assert_eq!(Program::from_bytes(b"\x01\x00"), Err(ReadError::BadMagic));
+        assert_eq!(
+            Program::from_bytes(b"ELF\x7f...."),
+            Err(ReadError::BadMagic)
+        );
+
+        let mut bytes = program().to_bytes();
+        bytes[4..6].copy_from_slice(&(VERSION_MAJOR + 1).to_le_bytes());
+        assert_eq!(
+            Program::from_bytes(&bytes),
+            Err(ReadError::UnsupportedVersion {
+                major: VERSION_MAJOR + 1,
+                minor: VERSION_MINOR
+            })
+        );
+    }
+
+    #[test]
+    fn skips_unknown_sections() {
+        let mut bytes = program().to_bytes();
+        // Minor version bump and the last two sections relabelled as unknown
+        bytes[6] = 9;
+        bytes[HEADER_SIZE + 3 * SECTION_ENTRY_SIZE] = 42;
+        bytes[HEADER_SIZE + 4 * SECTION_ENTRY_SIZE] = 43;
+
+        let mut expected = program();
+        expected.debug.clear();
+        expected.functions.clear();
+        assert_eq!(Program::from_bytes(&bytes), Ok(expected));
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = program().to_bytes();
+        let len = bytes.len();
+
+        assert_eq!(
+            Program::from_bytes(&bytes[..len - 1]),
+            Err(ReadError::Truncated { offset: len - 1 })
+        );
+        assert_eq!(
+            Program::from_bytes(&bytes[..10]),
+            Err(ReadError::Truncated { offset: 10 })
+        );
+    }
+
+    #[test]
+    fn corrupt_code() {
+        let mut bytes = program().to_bytes();
+        // Opcode of the second instruction
+        let code_offset = HEADER_SIZE + 5 * SECTION_ENTRY_SIZE;
+        bytes[code_offset + 6] = 0xff;
+
+        assert_eq!(
+            Program::from_bytes(&bytes),
+            Err(ReadError::Code(DecodeError::UnknownOpcode {
+                opcode: 0xff,
+                offset: 6
+            }))
+        );
+    }
+}
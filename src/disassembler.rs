@@ -0,0 +1,276 @@
+//! Disassembler turning programs back into assembly.
+//!
+//! The listing is accepted by the assembler which produces the same program from it: the
+//! functions, the labels of the symbol table and the line numbers of the debug info are kept.
+//! Branch and call targets without a name get local `.L<index>` labels which the assembler does
+//! not add to the symbol table. Every instruction is followed by a comment with its byte offset
+//! in the code section and its index.
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+
+use crate::bytecode::{encode, Inst};
+use crate::container::{FunctionInfo, Program, Symbol};
+
+/// Width of the instruction text, the comments start after it.
+const INST_WIDTH: usize = 32;
+
+/// Operands of `inst` as the assembler expects them, `target` is the name of the branch or call
+/// target.
+pub fn operands(inst: Inst, target: &str) -> String {
+    match inst {
+        Inst::Mov(v1, v2) => format!("v{}, v{}", v1, v2),
+        Inst::Movi(v, imm) => format!("v{}, {}", v, imm),
+        Inst::Ldai(imm)
+        | Inst::Addi(imm)
+        | Inst::Subi(imm)
+        | Inst::Muli(imm)
+        | Inst::Divi(imm)
+        | Inst::Modi(imm) => imm.to_string(),
+        Inst::Lda(v)
+        | Inst::Sta(v)
+        | Inst::Add(v)
+        | Inst::Dec(v)
+        | Inst::Sub(v)
+        | Inst::Mul(v)
+        | Inst::Div(v)
+        | Inst::Mod(v)
+        | Inst::And(v)
+        | Inst::Or(v)
+        | Inst::Xor(v)
+        | Inst::Shl(v)
+        | Inst::Shr(v)
+        | Inst::Sar(v) => format!("v{}", v),
+        Inst::Jmp(_) => target.to_string(),
+        Inst::Bne(v1, v2, _)
+        | Inst::Beq(v1, v2, _)
+        | Inst::Blt(v1, v2, _)
+        | Inst::Ble(v1, v2, _)
+        | Inst::Bgt(v1, v2, _)
+        | Inst::Bge(v1, v2, _)
+        | Inst::Bltu(v1, v2, _)
+        | Inst::Bleu(v1, v2, _)
+        | Inst::Bgtu(v1, v2, _)
+        | Inst::Bgeu(v1, v2, _) => format!("v{}, v{}, {}", v1, v2, target),
+        Inst::Call(_, argc) => format!("{}, {}", target, argc),
+        Inst::Print | Inst::Neg | Inst::Not | Inst::Ret => String::new(),
+    }
+}
+
+/// Function a call to `target` resolves to, the assembler looks functions up before labels.
+fn callee(functions: &[&FunctionInfo], target: u32) -> Option<String> {
+    functions
+        .iter()
+        .find(|f| f.entry == target)
+        .map(|f| f.name.clone())
+}
+
+/// Disassemble `program` into assembly source.
+///
+/// The entry point is not written out: the assembler starts the program at function `main` if
+/// there is one and at the first instruction otherwise.
+pub fn disassemble(program: &Program) -> String {
+    let code = &program.code;
+
+    let mut functions: Vec<&FunctionInfo> = program.functions.iter().collect();
+    functions.sort_by_key(|f| f.entry);
+    let mut symbols: Vec<&Symbol> = program.symbols.iter().collect();
+    symbols.sort_by_key(|s| s.index);
+
+    let mut labels: HashMap<u32, String> = HashMap::new();
+    for symbol in &symbols {
+        labels
+            .entry(symbol.index)
+            .or_insert_with(|| symbol.name.clone());
+    }
+    let mut locals: HashSet<u32> = HashSet::new();
+    for inst in code {
+        let target = match inst {
+            Inst::Call(target, _) if callee(&functions, *target).is_none() => *target,
+            _ => match inst.branch_target() {
+                Some(target) => target,
+                None => continue,
+            },
+        };
+        if let Entry::Vacant(entry) = labels.entry(target) {
+            entry.insert(format!(".L{}", target));
+            locals.insert(target);
+        }
+    }
+
+    let mut debug: HashMap<u32, u32> = HashMap::new();
+    for info in &program.debug {
+        debug.entry(info.index).or_insert(info.line);
+    }
+
+    let mut lines: Vec<String> = Vec::new();
+    // Difference between the line numbers in the debug info and the ones of the listing
+    let mut line_offset: i64 = 0;
+    let mut in_function = false;
+    let mut offset = 0;
+    let (mut next_function, mut next_symbol) = (0, 0);
+
+    for index in 0..=code.len() as u32 {
+        while next_function < functions.len() && functions[next_function].entry == index {
+            let function = functions[next_function];
+            if in_function {
+                lines.push(".end".to_string());
+            }
+            lines.push(format!(".function {}, {}", function.name, function.nregs));
+            in_function = true;
+            next_function += 1;
+        }
+        while next_symbol < symbols.len() && symbols[next_symbol].index == index {
+            lines.push(format!("{}:", symbols[next_symbol].name));
+            next_symbol += 1;
+        }
+        if locals.contains(&index) {
+            lines.push(format!(".L{}:", index));
+        }
+
+        let inst = match code.get(index as usize) {
+            Some(inst) => *inst,
+            None => break,
+        };
+        if let Some(&line) = debug.get(&index) {
+            if lines.len() as i64 + 1 + line_offset != line as i64 {
+                lines.push(format!(".line {}", line));
+                line_offset = line as i64 - (lines.len() as i64 + 1);
+            }
+        }
+
+        let target = match inst {
+            Inst::Call(target, _) => callee(&functions, target),
+            _ => None,
+        }
+        .or_else(|| match inst {
+            Inst::Call(target, _) => labels.get(&target).cloned(),
+            _ => inst.branch_target().and_then(|t| labels.get(&t).cloned()),
+        })
+        .unwrap_or_default();
+        let text = format!("    {} {}", inst.mnemonic(), operands(inst, &target));
+        lines.push(format!(
+            "{:<width$}; {:#06x} {}",
+            text.trim_end(),
+            offset,
+            index,
+            width = INST_WIDTH
+        ));
+
+        let mut bytes = Vec::new();
+        encode(inst, &mut bytes);
+        offset += bytes.len();
+    }
+    if in_function {
+        lines.push(".end".to_string());
+    }
+
+    let mut text = lines.join("\n");
+    text.push('\n');
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+    use crate::bytecode::Inst;
+    use crate::container::Program;
+    use crate::disassembler::disassemble;
+
+    /// Assemble `source`, disassemble the program and assemble the listing again.
+    fn round_trip(source: &str) {
+        let program = assemble("test.S", source).unwrap();
+        let listing = disassemble(&program);
+        let again = assemble("listing.S", &listing).unwrap();
+
+        assert_eq!(again.to_bytes(), program.to_bytes(), "{}", listing);
+    }
+
+    #[test]
+    fn listing() {
+        let program = assemble(
+            "test.S",
+            "
+            .function main, 2
+                movi v0, 5
+            loop:
+                call twice, 1
+                print
+                dec v0
+                bgtu v0, v1, loop
+                jmp .Ldone
+                neg
+            .Ldone:
+                ret
+            .end
+
+            .function twice, 1
+                lda v0
+                add v0
+                ret
+            .end
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            disassemble(&program),
+            ".function main, 2
+.line 3
+    movi v0, 5                  ; 0x0000 0
+loop:
+    call twice, 1               ; 0x0006 1
+    print                       ; 0x000c 2
+    dec v0                      ; 0x000d 3
+    bgtu v0, v1, loop           ; 0x000f 4
+    jmp .L7                     ; 0x0016 5
+    neg                         ; 0x001b 6
+.L7:
+    ret                         ; 0x001c 7
+.end
+.function twice, 1
+.line 16
+    lda v0                      ; 0x001d 8
+    add v0                      ; 0x001f 9
+    ret                         ; 0x0021 10
+.end
+"
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip(include_str!("../examples/fibonacci.S"));
+        round_trip(include_str!("../examples/loop_release_37_seconds.S"));
+        round_trip(
+            "
+            ; Labels at the end and calls to labels
+            .function main, 1
+                call helper, 0
+                movi v0, 'x'
+                call Linner, 1
+                ret
+            .end
+            Linner:
+            helper: ldai 0xffffffff
+                beq v0, v0, Lend
+                jmp .Lend
+            .Lend:
+            Lend:
+            ",
+        );
+    }
+
+    #[test]
+    fn without_debug_info() {
+        let program = Program {
+            code: vec![Inst::Jmp(1), Inst::Print, Inst::Jmp(0)],
+            ..Program::default()
+        };
+
+        assert_eq!(
+            assemble("listing.S", &disassemble(&program)).unwrap().code,
+            program.code
+        );
+    }
+}
@@ -4,39 +4,51 @@ use std::ops::{Index, IndexMut};
 
 use crate::bytecode;
 
-/// Find first instructions in the basic blocks also known as "leaders"
-pub fn find_leaders(bc: Vec<bytecode::Inst>) -> Vec<usize> {
-    let mut leaders: Vec<usize> = Vec::new();
+pub mod cfg;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub mod codegen;
+pub mod dominators;
+pub mod dot;
+pub mod liveness;
+pub mod loops;
+pub mod lower;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub mod memory;
+pub mod regalloc;
+pub mod text;
+pub mod verifier;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub mod x86_64;
+
+/// Find first instructions in the basic blocks also known as "leaders": the first instruction,
+/// branch and call targets and the instructions following branches and returns. The leaders are
+/// sorted and branch targets past the last instruction are left out.
+pub fn find_leaders(bc: &[bytecode::Inst]) -> Vec<usize> {
+    let mut leaders: BTreeSet<usize> = BTreeSet::new();
 
     if bc.is_empty() {
         // return an empty vector with no RawBlock's
         return Vec::new();
     }
 
-    leaders.push(0);
+    leaders.insert(0);
 
-    for i in 1..bc.len() {
-        if bc[i].is_branch() {
-            if let bytecode::Inst::Bne(_v1, _v2, imm) = bc[i] {
-                leaders.push(imm as usize);
-            }
-            if i + 1 < bc.len() {
-                leaders.push(i + 1);
+    for (i, inst) in bc.iter().enumerate() {
+        let target = match inst {
+            bytecode::Inst::Call(target, _) => Some(*target),
+            _ => inst.branch_target(),
+        };
+        if let Some(imm) = target {
+            if (imm as usize) < bc.len() {
+                leaders.insert(imm as usize);
             }
-        } else {
-            continue;
         }
-    }
-
-    #[cfg(debug_assertions)]
-    {
-        println!("The leaders:");
-        for l in &leaders {
-            println!("{}", l);
+        if (inst.is_branch() || *inst == bytecode::Inst::Ret) && i + 1 < bc.len() {
+            leaders.insert(i + 1);
         }
     }
 
-    leaders
+    leaders.into_iter().collect()
 }
 
 struct SecondaryMap<K, V> {
@@ -93,13 +105,12 @@ where
     }
 }
 
-type Block = u32;
+pub type Block = u32;
 
 #[derive(Default)]
 struct BlockNode {
     prev: Option<Block>,
     next: Option<Block>,
-    first_phi: Option<Inst>,
     first_inst: Option<Inst>,
     last_inst: Option<Inst>,
 }
@@ -134,7 +145,7 @@ impl<'f> IntoIterator for &'f Layout {
     }
 }
 
-type Inst = u32;
+pub type Inst = u32;
 
 #[derive(Default)]
 struct InstNode {
@@ -150,6 +161,12 @@ pub struct Layout {
     last_block: Option<Block>,
 }
 
+impl Default for Layout {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Layout {
     /// Create a new empty `Layout`.
     pub fn new() -> Self {
@@ -188,8 +205,8 @@ impl Layout {
         {
             let node = &mut self.blocks[block];
             debug_assert!(node.first_inst.is_none() && node.last_inst.is_none());
-            node.prev = self.last_block.into();
-            node.next = None.into();
+            node.prev = self.last_block;
+            node.next = None;
         }
 
         if let Some(last) = self.last_block {
@@ -202,7 +219,7 @@ impl Layout {
 
     /// Get the block containing `inst`, or `None` if `inst` is not inserted in the layout.
     pub fn inst_block(&self, inst: Inst) -> Option<Block> {
-        self.insts[inst].block.into()
+        self.insts[inst].block
     }
 
     /// Append `inst` to the end of `block`.
@@ -232,7 +249,7 @@ impl Layout {
     }
 
     /// Return an iterator over all blocks in layout order.
-    pub fn blocks(&self) -> Blocks {
+    pub fn blocks(&self) -> Blocks<'_> {
         Blocks {
             layout: self,
             next: self.first_block,
@@ -243,54 +260,269 @@ impl Layout {
     pub fn next_block(&self, block: Block) -> Option<Block> {
         self.blocks[block].next
     }
+
+    /// Insert `inst` at the start of `block`.
+    pub fn prepend_inst(&mut self, inst: Inst, block: Block) {
+        debug_assert_eq!(self.inst_block(inst), None);
+        debug_assert!(
+            self.is_block_inserted(block),
+            "Cannot prepend instructions to block not in layout"
+        );
+
+        let first = self.blocks[block].first_inst;
+        {
+            let inst_node = &mut self.insts[inst];
+            inst_node.block = block.into();
+            inst_node.next = first;
+        }
+        match first {
+            Some(first) => self.insts[first].prev = inst.into(),
+            None => self.blocks[block].last_inst = inst.into(),
+        }
+        self.blocks[block].first_inst = inst.into();
+    }
+
+    /// Remove `inst` from the layout.
+    pub fn remove_inst(&mut self, inst: Inst) {
+        let block = self.inst_block(inst).expect("Instruction already removed");
+        let (prev, next) = {
+            let node = &mut self.insts[inst];
+            let links = (node.prev, node.next);
+            *node = InstNode::default();
+            links
+        };
+
+        match prev {
+            Some(prev) => self.insts[prev].next = next,
+            None => self.blocks[block].first_inst = next,
+        }
+        match next {
+            Some(next) => self.insts[next].prev = prev,
+            None => self.blocks[block].last_inst = prev,
+        }
+    }
+
+    pub fn first_inst(&self, block: Block) -> Option<Inst> {
+        self.blocks[block].first_inst
+    }
+
+    pub fn last_inst(&self, block: Block) -> Option<Inst> {
+        self.blocks[block].last_inst
+    }
+
+    /// Get the instruction following `inst` in its block.
+    pub fn next_inst(&self, inst: Inst) -> Option<Inst> {
+        self.insts[inst].next
+    }
+
+    /// Return an iterator over the instructions of `block` in layout order.
+    pub fn block_insts(&self, block: Block) -> Insts<'_> {
+        Insts {
+            layout: self,
+            next: self.blocks[block].first_inst,
+        }
+    }
+}
+
+/// Iterate over the instructions of a block in layout order. See `Layout::block_insts()`.
+pub struct Insts<'f> {
+    layout: &'f Layout,
+    next: Option<Inst>,
+}
+
+impl<'f> Iterator for Insts<'f> {
+    type Item = Inst;
+
+    fn next(&mut self) -> Option<Inst> {
+        let inst = self.next?;
+        self.next = self.layout.next_inst(inst);
+        Some(inst)
+    }
 }
 
-enum Opcode {
+/// Interpreter state an SSA value is loaded from or stored to: a register of the frame or the
+/// accumulator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Var {
+    Reg(u8),
+    Acc,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Opcode {
     Constant,
     Add,
     Sub,
+    Mul,
+    Div,
+    Mod,
+    Neg,
+    And,
+    Or,
+    Xor,
+    Not,
+    Shl,
+    Shr,
+    Sar,
+    Beq,
     Bne,
+    Blt,
+    Ble,
+    Bgt,
+    Bge,
+    Bltu,
+    Bleu,
+    Bgtu,
+    Bgeu,
+    Jump,
+    Exit,
     Phi,
+    Load,
+    Store,
+    Print,
 }
 
-enum InstData {
+#[derive(Clone, Debug, PartialEq)]
+pub enum InstData {
     Constant {
         opcode: Opcode,
-        value: u32,
+        value: u64,
+    },
+    /// `neg` and `not`.
+    Unary {
+        opcode: Opcode,
+        input: Inst,
     },
     Binary {
         opcode: Opcode,
         inputs: [Inst; 2],
     },
-    Bne {
+    /// Arithmetic which fails like the bytecode instruction at `exit`: overflow, underflow and
+    /// division by zero leave the compiled code and resume the interpreter there.
+    Checked {
+        opcode: Opcode,
+        inputs: [Inst; 2],
+        exit: u32,
+    },
+    /// Conditional branch to the first successor if the condition holds and to the second one
+    /// otherwise.
+    Branch {
         opcode: Opcode,
         inputs: [Inst; 2],
         succs: [Block; 2],
     },
+    Jump {
+        opcode: Opcode,
+        dest: Block,
+    },
+    /// Leave the compiled code and resume the interpreter at bytecode index `pc`.
+    Exit {
+        opcode: Opcode,
+        pc: u32,
+    },
+    /// The inputs are in the order of the predecessors of the block.
     Phi {
         opcode: Opcode,
         inputs: Vec<Inst>,
     },
+    Load {
+        opcode: Opcode,
+        var: Var,
+    },
+    /// Print the input. When the output fails the compiled code is left at `exit` where the
+    /// interpreter prints again and reports the error.
+    Print {
+        opcode: Opcode,
+        input: Inst,
+        exit: u32,
+    },
+    Store {
+        opcode: Opcode,
+        var: Var,
+        input: Inst,
+    },
 }
 
 impl InstData {
-    fn inputs(&self) -> Option<Vec<Inst>> {
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            Self::Constant { opcode, .. }
+            | Self::Unary { opcode, .. }
+            | Self::Binary { opcode, .. }
+            | Self::Checked { opcode, .. }
+            | Self::Branch { opcode, .. }
+            | Self::Jump { opcode, .. }
+            | Self::Exit { opcode, .. }
+            | Self::Phi { opcode, .. }
+            | Self::Load { opcode, .. }
+            | Self::Store { opcode, .. }
+            | Self::Print { opcode, .. } => *opcode,
+        }
+    }
+
+    pub fn inputs(&self) -> &[Inst] {
+        match self {
+            Self::Unary { input, .. } | Self::Store { input, .. } | Self::Print { input, .. } => {
+                std::slice::from_ref(input)
+            }
+            Self::Binary { inputs, .. }
+            | Self::Checked { inputs, .. }
+            | Self::Branch { inputs, .. } => inputs,
+            Self::Phi { inputs, .. } => inputs,
+            Self::Constant { .. } | Self::Jump { .. } | Self::Exit { .. } | Self::Load { .. } => {
+                &[]
+            }
+        }
+    }
+
+    fn inputs_mut(&mut self) -> &mut [Inst] {
+        match self {
+            Self::Unary { input, .. } | Self::Store { input, .. } | Self::Print { input, .. } => {
+                std::slice::from_mut(input)
+            }
+            Self::Binary { inputs, .. }
+            | Self::Checked { inputs, .. }
+            | Self::Branch { inputs, .. } => inputs,
+            Self::Phi { inputs, .. } => inputs,
+            Self::Constant { .. } | Self::Jump { .. } | Self::Exit { .. } | Self::Load { .. } => {
+                &mut []
+            }
+        }
+    }
+
+    /// Blocks control continues with after a terminator.
+    pub fn succs(&self) -> &[Block] {
         match self {
-            Self::Constant { .. } => None,
-            Self::Binary { opcode, inputs } => Some(vec![inputs[0], inputs[1]]),
-            Self::Bne { opcode, inputs, .. } => Some(vec![inputs[0], inputs[1]]),
-            Self::Phi { opcode, inputs } => Some(inputs.clone()),
+            Self::Branch { succs, .. } => succs,
+            Self::Jump { dest, .. } => std::slice::from_ref(dest),
+            _ => &[],
         }
     }
+
+    /// Does the instruction end a block?
+    pub fn is_terminator(&self) -> bool {
+        matches!(
+            self,
+            Self::Branch { .. } | Self::Jump { .. } | Self::Exit { .. }
+        )
+    }
+
+    /// Does the instruction define an SSA value?
+    pub fn has_result(&self) -> bool {
+        !self.is_terminator() && !matches!(self, Self::Store { .. } | Self::Print { .. })
+    }
 }
 
-struct DataFlowGraph {
+pub struct DataFlowGraph {
     // Data about all of the instructions in the function, including opcodes and inputs. The
     // instructions in this map are not in program order.
     insts: HashMap<Inst, InstData>,
 
     // Users of instructions
     users: SecondaryMap<Inst, BTreeSet<Inst>>,
+
+    // Number of the next instruction, the numbers of removed instructions are not reused
+    next_inst: Inst,
 }
 
 impl DataFlowGraph {
@@ -298,19 +530,64 @@ impl DataFlowGraph {
         Self {
             insts: HashMap::new(),
             users: SecondaryMap::new(),
+            next_inst: 1,
         }
     }
 
     fn make_inst(&mut self, data: InstData) -> Inst {
-        let ret = (self.insts.len() + 1) as u32;
-        if let Some(inputs) = data.inputs() {
-            for input in inputs {
-                self.users[input].insert(ret);
+        let ret = self.next_inst;
+        self.insert_inst(ret, data);
+        ret
+    }
+
+    /// Add an instruction numbered `inst`, the following instructions are numbered after it.
+    fn insert_inst(&mut self, inst: Inst, data: InstData) {
+        debug_assert!(!self.insts.contains_key(&inst));
+        self.next_inst = self.next_inst.max(inst + 1);
+        for input in data.inputs() {
+            self.users[*input].insert(inst);
+        }
+
+        self.insts.insert(inst, data);
+    }
+
+    pub fn inst_data(&self, inst: Inst) -> &InstData {
+        &self.insts[&inst]
+    }
+
+    /// Instructions using the value of `inst`.
+    pub fn users(&self, inst: Inst) -> &BTreeSet<Inst> {
+        &self.users[inst]
+    }
+
+    /// Append `input` to the inputs of phi `phi`.
+    fn push_phi_input(&mut self, phi: Inst, input: Inst) {
+        match self.insts.get_mut(&phi) {
+            Some(InstData::Phi { inputs, .. }) => inputs.push(input),
+            _ => panic!("{} is not a phi", phi),
+        }
+        self.users[input].insert(phi);
+    }
+
+    /// Make all the users of `old` use `new` instead.
+    fn replace_uses(&mut self, old: Inst, new: Inst) {
+        for user in std::mem::take(&mut self.users[old]) {
+            for input in self.insts.get_mut(&user).unwrap().inputs_mut() {
+                if *input == old {
+                    *input = new;
+                }
             }
+            self.users[new].insert(user);
         }
+    }
 
-        self.insts.insert(ret, data);
-        ret
+    /// Remove `inst` which has no users.
+    fn remove_inst(&mut self, inst: Inst) {
+        debug_assert!(self.users[inst].is_empty());
+        let data = self.insts.remove(&inst).unwrap();
+        for input in data.inputs() {
+            self.users[*input].remove(&inst);
+        }
     }
 }
 
@@ -320,7 +597,8 @@ struct CFGNode {
     succs: BTreeSet<Block>,
 }
 
-struct Function {
+/// SSA form of a region of bytecode.
+pub struct Function {
     dfg: DataFlowGraph,
     layout: Layout,
     cfg: SecondaryMap<Block, CFGNode>,
@@ -334,10 +612,38 @@ impl Function {
             cfg: SecondaryMap::new(),
         }
     }
+
+    pub fn dfg(&self) -> &DataFlowGraph {
+        &self.dfg
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// The first block of the layout, control enters the function there.
+    pub fn entry_block(&self) -> Option<Block> {
+        self.layout.first_block
+    }
+
+    pub fn preds(&self, block: Block) -> impl Iterator<Item = Block> + '_ {
+        self.cfg[block].preds.iter().copied()
+    }
+
+    pub fn succs(&self, block: Block) -> impl Iterator<Item = Block> + '_ {
+        self.cfg[block].succs.iter().copied()
+    }
+
+    fn add_edge(&mut self, from: Block, to: Block) {
+        self.cfg[from].succs.insert(to);
+        self.cfg[to].preds.insert(from);
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::bytecode::Inst;
+    use crate::jit::find_leaders;
     use crate::jit::DataFlowGraph;
     use crate::jit::InstData;
     use crate::jit::Layout;
@@ -396,4 +702,28 @@ mod tests {
         assert_eq!(const2, 2);
         assert_eq!(add, 3);
     }
+
+    #[test]
+    fn leaders() {
+        let bc = vec![
+            Inst::Movi(0, 0),
+            Inst::Movi(1, 5),
+            Inst::Dec(1),
+            Inst::Bgtu(1, 0, 2),
+            Inst::Jmp(6),
+            Inst::Print,
+            Inst::Beq(0, 1, 1),
+        ];
+        assert_eq!(find_leaders(&bc), vec![0, 1, 2, 4, 5, 6]);
+
+        // A branch at the first instruction, a call and a return
+        let bc = vec![
+            Inst::Bne(0, 1, 2),
+            Inst::Call(4, 0),
+            Inst::Ret,
+            Inst::Print,
+            Inst::Jmp(9),
+        ];
+        assert_eq!(find_leaders(&bc), vec![0, 1, 2, 3, 4]);
+    }
 }
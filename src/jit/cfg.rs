@@ -0,0 +1,188 @@
+//! Control-flow graph of bytecode.
+
+use std::ops::Range;
+
+use super::{find_leaders, Block, CFGNode};
+use crate::bytecode::Inst;
+
+/// How control gets from one block to another.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EdgeKind {
+    /// The branch ending the block is taken.
+    Taken,
+    /// Execution continues with the instruction following the block.
+    Fallthrough,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Edge {
+    pub from: Block,
+    pub to: Block,
+    pub kind: EdgeKind,
+}
+
+/// Basic blocks of bytecode and the edges between them. The blocks are numbered in bytecode
+/// order starting from 0.
+///
+/// A call does not end a block, control continues after it once the callee returns. Branches to
+/// the end of the code halt and have no edge.
+pub struct ControlFlowGraph {
+    /// Instructions of every block
+    ranges: Vec<Range<usize>>,
+    nodes: Vec<CFGNode>,
+    edges: Vec<Edge>,
+}
+
+impl ControlFlowGraph {
+    /// Iterate over the blocks in bytecode order.
+    pub fn blocks(&self) -> impl Iterator<Item = Block> {
+        0..self.ranges.len() as Block
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.ranges.len()
+    }
+
+    /// Indices of the instructions of `block`.
+    pub fn insts(&self, block: Block) -> Range<usize> {
+        self.ranges[block as usize].clone()
+    }
+
+    /// Block starting at instruction `pc`.
+    pub fn block_at(&self, pc: usize) -> Option<Block> {
+        self.ranges
+            .binary_search_by_key(&pc, |range| range.start)
+            .ok()
+            .map(|block| block as Block)
+    }
+
+    /// Block containing instruction `pc`.
+    pub fn block_of(&self, pc: usize) -> Option<Block> {
+        let block = self.ranges.partition_point(|range| range.end <= pc);
+        match self.ranges.get(block) {
+            Some(range) if range.contains(&pc) => Some(block as Block),
+            _ => None,
+        }
+    }
+
+    pub fn preds(&self, block: Block) -> impl Iterator<Item = Block> + '_ {
+        self.nodes[block as usize].preds.iter().copied()
+    }
+
+    pub fn succs(&self, block: Block) -> impl Iterator<Item = Block> + '_ {
+        self.nodes[block as usize].succs.iter().copied()
+    }
+
+    /// Iterate over the edges, ordered by their source block. A conditional branch to the
+    /// following instruction has both a taken and a fallthrough edge to the same block.
+    pub fn edges(&self) -> impl Iterator<Item = &Edge> {
+        self.edges.iter()
+    }
+
+    fn add_edge(&mut self, from: Block, to: Block, kind: EdgeKind) {
+        self.nodes[from as usize].succs.insert(to);
+        self.nodes[to as usize].preds.insert(from);
+        self.edges.push(Edge { from, to, kind });
+    }
+}
+
+/// Split `code` into basic blocks starting at the leaders and connect them.
+pub fn build_cfg(code: &[Inst]) -> ControlFlowGraph {
+    let leaders = find_leaders(code);
+    let ranges: Vec<Range<usize>> = leaders
+        .iter()
+        .enumerate()
+        .map(|(i, start)| *start..leaders.get(i + 1).copied().unwrap_or(code.len()))
+        .collect();
+    let mut cfg = ControlFlowGraph {
+        nodes: ranges.iter().map(|_| CFGNode::default()).collect(),
+        ranges,
+        edges: Vec::new(),
+    };
+
+    for block in cfg.blocks() {
+        let last = cfg.insts(block).end - 1;
+        let inst = code[last];
+
+        if let Some(to) = inst.branch_target().and_then(|t| cfg.block_at(t as usize)) {
+            cfg.add_edge(block, to, EdgeKind::Taken);
+        }
+        if inst.falls_through() && last + 1 < code.len() {
+            cfg.add_edge(block, block + 1, EdgeKind::Fallthrough);
+        }
+    }
+
+    cfg
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+    use crate::bytecode::Inst;
+    use crate::jit::cfg::{build_cfg, Edge, EdgeKind};
+
+    fn edge(from: u32, to: u32, kind: EdgeKind) -> Edge {
+        Edge { from, to, kind }
+    }
+
+    #[test]
+    fn fibonacci() {
+        let program = assemble("fibonacci.S", include_str!("../../examples/fibonacci.S")).unwrap();
+        let cfg = build_cfg(&program.code);
+
+        assert_eq!(cfg.num_blocks(), 2);
+        assert_eq!(cfg.insts(0), 0..6);
+        assert_eq!(cfg.insts(1), 6..13);
+        assert_eq!(
+            cfg.edges().copied().collect::<Vec<_>>(),
+            vec![
+                edge(0, 1, EdgeKind::Fallthrough),
+                edge(1, 1, EdgeKind::Taken),
+            ]
+        );
+        assert_eq!(cfg.preds(1).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(cfg.succs(1).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(cfg.block_of(9), Some(1));
+        assert_eq!(cfg.block_of(13), None);
+    }
+
+    #[test]
+    fn branches() {
+        let code = vec![
+            Inst::Bne(0, 1, 3),
+            Inst::Call(5, 0),
+            Inst::Jmp(6),
+            Inst::Beq(0, 1, 4),
+            Inst::Print,
+            Inst::Ret,
+        ];
+        let cfg = build_cfg(&code);
+
+        assert_eq!(
+            cfg.blocks().map(|b| cfg.insts(b)).collect::<Vec<_>>(),
+            vec![0..1, 1..3, 3..4, 4..5, 5..6]
+        );
+        assert_eq!(
+            cfg.edges().copied().collect::<Vec<_>>(),
+            vec![
+                edge(0, 2, EdgeKind::Taken),
+                edge(0, 1, EdgeKind::Fallthrough),
+                edge(2, 3, EdgeKind::Taken),
+                edge(2, 3, EdgeKind::Fallthrough),
+                edge(3, 4, EdgeKind::Fallthrough),
+            ]
+        );
+        assert_eq!(cfg.succs(1).count(), 0);
+        assert_eq!(cfg.preds(3).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(cfg.block_at(5), Some(4));
+        assert_eq!(cfg.block_at(2), None);
+    }
+
+    #[test]
+    fn empty() {
+        let cfg = build_cfg(&[]);
+
+        assert_eq!(cfg.num_blocks(), 0);
+        assert_eq!(cfg.edges().count(), 0);
+    }
+}
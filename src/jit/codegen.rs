@@ -0,0 +1,731 @@
+//! x86-64 code generation for SSA functions.
+//!
+//! Values live where `regalloc` puts them, in registers or in slots of the stack frame.
+//! Instructions compute their results in scratch registers which are never allocated: `rax`,
+//! `rcx`, `rdx`, `rsi` and `rdi`. Phis are resolved on the edges to their block with parallel
+//! copies. Allocated registers which calls clobber are saved around the calls of `print`.
+//!
+//! The compiled code follows the System V calling convention and takes a `Context` holding the
+//! register window of the frame, the accumulator and the output. It returns the bytecode index
+//! it exits to, the interpreter continues there.
+
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::fmt;
+use std::io;
+
+use super::liveness::phi_inputs;
+use super::memory::ExecutableMemory;
+use super::regalloc::{parallel_moves, Allocation, Location};
+use super::x86_64::{AluOp, Assembler, Cond, Label, Mem, Operand, Reg, ShiftOp, UnaryOp};
+use super::{Block, Function, Inst, InstData, Opcode, Var};
+
+/// Print `value` to `out`, returns nonzero if the output failed.
+pub type PrintFn = extern "C" fn(out: *mut c_void, value: u64) -> u64;
+
+/// Interpreter state compiled code runs on.
+#[repr(C)]
+pub struct Context {
+    /// Registers of the frame
+    pub regs: *mut u64,
+    pub acc: u64,
+    pub print: PrintFn,
+    /// Passed to `print`
+    pub out: *mut c_void,
+}
+
+// Offsets of the fields of `Context`
+const REGS: i32 = 0;
+const ACC: i32 = 8;
+const PRINT: i32 = 16;
+const OUT: i32 = 24;
+
+/// Register holding the context in the compiled code.
+const CONTEXT: Reg = Reg::Rbx;
+
+#[derive(Debug)]
+pub enum CompileError {
+    /// The instruction cannot be compiled.
+    Unsupported { inst: Inst, opcode: Opcode },
+    /// The executable memory cannot be allocated.
+    Memory(io::Error),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unsupported { inst, opcode } => {
+                write!(f, "inst{}: {} is not supported", inst, opcode)
+            }
+            Self::Memory(e) => write!(f, "cannot allocate executable memory: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// Machine code of a function.
+pub struct CompiledCode {
+    memory: ExecutableMemory,
+}
+
+impl CompiledCode {
+    /// Run the code on `ctx` and return the bytecode index it exits to.
+    ///
+    /// # Safety
+    ///
+    /// `ctx.regs` must point to at least as many registers as the code accesses, which holds for
+    /// code compiled from a verified program running in a frame of the function it was compiled
+    /// from. `ctx.print` must be safe to call with `ctx.out`.
+    pub unsafe fn run(&self, ctx: &mut Context) -> u32 {
+        let entry: extern "C" fn(*mut Context) -> u64 = std::mem::transmute(self.memory.as_ptr());
+        entry(ctx) as u32
+    }
+}
+
+/// Condition under which the branch `opcode` is taken.
+fn cond(opcode: Opcode) -> Cond {
+    match opcode {
+        Opcode::Beq => Cond::E,
+        Opcode::Bne => Cond::Ne,
+        Opcode::Blt => Cond::L,
+        Opcode::Ble => Cond::Le,
+        Opcode::Bgt => Cond::G,
+        Opcode::Bge => Cond::Ge,
+        Opcode::Bltu => Cond::B,
+        Opcode::Bleu => Cond::Be,
+        Opcode::Bgtu => Cond::A,
+        Opcode::Bgeu => Cond::Ae,
+        _ => panic!("{} is not a branch", opcode),
+    }
+}
+
+/// Registers the values are allocated to, values in the callee-saved ones survive calls.
+const ALLOCATABLE: [Reg; 8] = [
+    Reg::R12,
+    Reg::R13,
+    Reg::R14,
+    Reg::R15,
+    Reg::R8,
+    Reg::R9,
+    Reg::R10,
+    Reg::R11,
+];
+
+fn is_callee_saved(reg: Reg) -> bool {
+    matches!(
+        reg,
+        Reg::Rbx | Reg::Rbp | Reg::R12 | Reg::R13 | Reg::R14 | Reg::R15
+    )
+}
+
+struct CodeGen<'a> {
+    func: &'a Function,
+    asm: Assembler,
+    alloc: Allocation,
+    /// Frame offset of the first stack slot
+    slots: i32,
+    /// Allocated registers which calls clobber
+    caller_saved: Vec<Reg>,
+    blocks: HashMap<Block, Label>,
+    /// Stubs leaving the code at the bytecode indices
+    exits: HashMap<u32, Label>,
+    /// Edges whose phi copies are emitted after the blocks
+    edges: Vec<(Label, Block, Block)>,
+    epilogue: Label,
+}
+
+impl<'a> CodeGen<'a> {
+    fn location(&self, value: Inst) -> Operand {
+        match self.alloc.location(value) {
+            Location::Reg(reg) => Operand::Reg(ALLOCATABLE[reg]),
+            Location::Stack(slot) => Operand::Mem(Mem {
+                base: Reg::Rbp,
+                disp: self.slots - 8 * slot as i32,
+            }),
+        }
+    }
+
+    fn exit(&mut self, pc: u32) -> Label {
+        let asm = &mut self.asm;
+        *self.exits.entry(pc).or_insert_with(|| asm.new_label())
+    }
+
+    /// Copy `src` to `dst`, memory to memory through `rcx`.
+    fn copy(&mut self, dst: Operand, src: Operand) {
+        match (dst, src) {
+            (Operand::Reg(dst), src) => self.asm.mov(dst, src),
+            (Operand::Mem(dst), Operand::Reg(src)) => self.asm.store(dst, src),
+            (Operand::Mem(dst), Operand::Mem(src)) => {
+                self.asm.mov(Reg::Rcx, src);
+                self.asm.store(dst, Reg::Rcx);
+            }
+        }
+    }
+
+    /// Load `value` into `reg`.
+    fn load(&mut self, reg: Reg, value: Inst) {
+        let location = self.location(value);
+        if location != Operand::Reg(reg) {
+            self.asm.mov(reg, location);
+        }
+    }
+
+    /// Move `reg` to the location of the result of `inst`.
+    fn define(&mut self, inst: Inst, reg: Reg) {
+        let location = self.location(inst);
+        if location != Operand::Reg(reg) {
+            self.copy(location, reg.into());
+        }
+    }
+
+    /// Memory of the interpreter variable `var`, the register window is loaded into `scratch`.
+    fn var(&mut self, var: Var, scratch: Reg) -> Mem {
+        match var {
+            Var::Reg(reg) => {
+                self.asm.mov(
+                    scratch,
+                    Mem {
+                        base: CONTEXT,
+                        disp: REGS,
+                    },
+                );
+                Mem {
+                    base: scratch,
+                    disp: reg as i32 * 8,
+                }
+            }
+            Var::Acc => Mem {
+                base: CONTEXT,
+                disp: ACC,
+            },
+        }
+    }
+
+    /// Copy the phi inputs of the edge from `pred` to `block`, `rax` breaks up cycles.
+    fn edge_copies(&mut self, pred: Block, block: Block) {
+        let moves: Vec<(Operand, Operand)> = phi_inputs(self.func, pred, block)
+            .into_iter()
+            .map(|(phi, input)| (self.location(input), self.location(phi)))
+            .collect();
+        for (src, dst) in parallel_moves(&moves, Reg::Rax.into()) {
+            self.copy(dst, src);
+        }
+    }
+
+    /// Jump from `pred` to `block` which follows `next` in the layout.
+    fn jump(&mut self, pred: Block, block: Block, next: Option<Block>) {
+        self.edge_copies(pred, block);
+        if next != Some(block) {
+            self.asm.jmp(self.blocks[&block]);
+        }
+    }
+
+    fn inst(&mut self, inst: Inst, next: Option<Block>) -> Result<(), CompileError> {
+        let block = self.func.layout.inst_block(inst).unwrap();
+        match self.func.dfg.inst_data(inst).clone() {
+            InstData::Constant { value, .. } => match self.location(inst) {
+                Operand::Reg(reg) => self.asm.mov_imm(reg, value),
+                Operand::Mem(_) => {
+                    self.asm.mov_imm(Reg::Rax, value);
+                    self.define(inst, Reg::Rax);
+                }
+            },
+            InstData::Unary { opcode, input } => {
+                let op = match opcode {
+                    Opcode::Neg => UnaryOp::Neg,
+                    _ => UnaryOp::Not,
+                };
+                self.load(Reg::Rax, input);
+                self.asm.unary(op, Reg::Rax);
+                self.define(inst, Reg::Rax);
+            }
+            InstData::Binary { opcode, inputs } => {
+                self.load(Reg::Rax, inputs[0]);
+                let rhs = self.location(inputs[1]);
+                match opcode {
+                    Opcode::Add => self.asm.alu(AluOp::Add, Reg::Rax, rhs),
+                    Opcode::Sub => self.asm.alu(AluOp::Sub, Reg::Rax, rhs),
+                    Opcode::And => self.asm.alu(AluOp::And, Reg::Rax, rhs),
+                    Opcode::Or => self.asm.alu(AluOp::Or, Reg::Rax, rhs),
+                    Opcode::Xor => self.asm.alu(AluOp::Xor, Reg::Rax, rhs),
+                    Opcode::Mul => self.asm.imul(Reg::Rax, rhs),
+                    Opcode::Shl | Opcode::Shr | Opcode::Sar => {
+                        let op = match opcode {
+                            Opcode::Shl => ShiftOp::Shl,
+                            Opcode::Shr => ShiftOp::Shr,
+                            _ => ShiftOp::Sar,
+                        };
+                        // The shift amount is taken modulo 64 by the hardware
+                        self.asm.mov(Reg::Rcx, rhs);
+                        self.asm.shift(op, Reg::Rax);
+                    }
+                    // Division by zero has no result without a check
+                    _ => return Err(CompileError::Unsupported { inst, opcode }),
+                }
+                self.define(inst, Reg::Rax);
+            }
+            InstData::Checked {
+                opcode,
+                inputs,
+                exit,
+            } => {
+                let exit = self.exit(exit);
+                let rhs = self.location(inputs[1]);
+                let result = match opcode {
+                    Opcode::Add | Opcode::Sub => {
+                        let op = match opcode {
+                            Opcode::Add => AluOp::Add,
+                            _ => AluOp::Sub,
+                        };
+                        self.load(Reg::Rax, inputs[0]);
+                        self.asm.alu(op, Reg::Rax, rhs);
+                        // Carry and borrow
+                        self.asm.jcc(Cond::B, exit);
+                        Reg::Rax
+                    }
+                    Opcode::Mul => {
+                        self.load(Reg::Rax, inputs[0]);
+                        self.asm.unary(UnaryOp::Mul, rhs);
+                        self.asm.jcc(Cond::O, exit);
+                        Reg::Rax
+                    }
+                    Opcode::Div | Opcode::Mod => {
+                        self.asm.mov(Reg::Rcx, rhs);
+                        self.asm.test(Reg::Rcx, Reg::Rcx);
+                        self.asm.jcc(Cond::E, exit);
+                        self.load(Reg::Rax, inputs[0]);
+                        self.asm.alu(AluOp::Xor, Reg::Rdx, Reg::Rdx);
+                        self.asm.unary(UnaryOp::Div, Reg::Rcx);
+                        if opcode == Opcode::Div {
+                            Reg::Rax
+                        } else {
+                            Reg::Rdx
+                        }
+                    }
+                    _ => return Err(CompileError::Unsupported { inst, opcode }),
+                };
+                self.define(inst, result);
+            }
+            InstData::Branch {
+                opcode,
+                inputs,
+                succs,
+            } => {
+                self.load(Reg::Rax, inputs[0]);
+                let rhs = self.location(inputs[1]);
+                self.asm.alu(AluOp::Cmp, Reg::Rax, rhs);
+
+                let [taken, not_taken] = succs;
+                if phi_inputs(self.func, block, taken).is_empty() {
+                    self.asm.jcc(cond(opcode), self.blocks[&taken]);
+                } else {
+                    let edge = self.asm.new_label();
+                    self.asm.jcc(cond(opcode), edge);
+                    self.edges.push((edge, block, taken));
+                }
+                self.jump(block, not_taken, next);
+            }
+            InstData::Jump { dest, .. } => self.jump(block, dest, next),
+            InstData::Exit { pc, .. } => {
+                let exit = self.exit(pc);
+                self.asm.jmp(exit);
+            }
+            // Assigned on the edges
+            InstData::Phi { .. } => (),
+            InstData::Load { var, .. } => {
+                let mem = self.var(var, Reg::Rcx);
+                self.asm.mov(Reg::Rax, mem);
+                self.define(inst, Reg::Rax);
+            }
+            InstData::Store { var, input, .. } => {
+                let reg = match self.location(input) {
+                    Operand::Reg(reg) => reg,
+                    Operand::Mem(_) => {
+                        self.load(Reg::Rax, input);
+                        Reg::Rax
+                    }
+                };
+                let mem = self.var(var, Reg::Rcx);
+                self.asm.store(mem, reg);
+            }
+            InstData::Print { input, exit, .. } => {
+                let exit = self.exit(exit);
+                self.asm.mov(
+                    Reg::Rdi,
+                    Mem {
+                        base: CONTEXT,
+                        disp: OUT,
+                    },
+                );
+                self.load(Reg::Rsi, input);
+                // An odd number of pushes is padded to keep the stack aligned
+                let saved = self.caller_saved.clone();
+                for reg in &saved {
+                    self.asm.push(*reg);
+                }
+                let padding = 8 * (saved.len() % 2) as i32;
+                if padding != 0 {
+                    self.asm.alu_imm(AluOp::Sub, Reg::Rsp, padding);
+                }
+                self.asm.call(Operand::Mem(Mem {
+                    base: CONTEXT,
+                    disp: PRINT,
+                }));
+                if padding != 0 {
+                    self.asm.alu_imm(AluOp::Add, Reg::Rsp, padding);
+                }
+                for reg in saved.iter().rev() {
+                    self.asm.pop(*reg);
+                }
+                self.asm.test(Reg::Rax, Reg::Rax);
+                self.asm.jcc(Cond::Ne, exit);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Generate the machine code of `func`.
+pub fn emit(func: &Function) -> Result<Vec<u8>, CompileError> {
+    let layout = &func.layout;
+    let mut asm = Assembler::new();
+    let blocks: HashMap<Block, Label> = layout
+        .blocks()
+        .map(|block| (block, asm.new_label()))
+        .collect();
+
+    let alloc = Allocation::compute(func, ALLOCATABLE.len());
+    let used: Vec<Reg> = alloc.used_regs().map(|reg| ALLOCATABLE[reg]).collect();
+    let (callee_saved, caller_saved): (Vec<Reg>, Vec<Reg>) =
+        used.into_iter().partition(|reg| is_callee_saved(*reg));
+
+    // rbx and the callee-saved registers are saved below rbp and the stack slots follow them
+    let saved = 1 + callee_saved.len() as i32;
+    asm.push(Reg::Rbp);
+    asm.mov(Reg::Rbp, Reg::Rsp);
+    asm.push(CONTEXT);
+    for reg in &callee_saved {
+        asm.push(*reg);
+    }
+    // The return address and rbp are on the stack, calls need it aligned to 16 bytes
+    let mut frame_size = 8 * alloc.stack_slots() as i32;
+    if (8 * saved + frame_size) % 16 != 0 {
+        frame_size += 8;
+    }
+    if frame_size != 0 {
+        asm.alu_imm(AluOp::Sub, Reg::Rsp, frame_size);
+    }
+    asm.mov(CONTEXT, Reg::Rdi);
+
+    let epilogue = asm.new_label();
+    let mut gen = CodeGen {
+        func,
+        asm,
+        alloc,
+        slots: -8 * (saved + 1),
+        caller_saved,
+        blocks,
+        exits: HashMap::new(),
+        edges: Vec::new(),
+        epilogue,
+    };
+    for block in layout.blocks() {
+        gen.asm.bind(gen.blocks[&block]);
+        let next = layout.next_block(block);
+        for inst in layout.block_insts(block) {
+            gen.inst(inst, next)?;
+        }
+    }
+    for (label, pred, block) in std::mem::take(&mut gen.edges) {
+        gen.asm.bind(label);
+        gen.jump(pred, block, None);
+    }
+
+    let mut exits: Vec<(u32, Label)> = gen.exits.iter().map(|(pc, l)| (*pc, *l)).collect();
+    exits.sort_unstable_by_key(|(pc, _)| *pc);
+    for (pc, label) in exits {
+        gen.asm.bind(label);
+        gen.asm.mov_imm(Reg::Rax, pc as u64);
+        gen.asm.jmp(gen.epilogue);
+    }
+    gen.asm.bind(gen.epilogue);
+    gen.asm.lea(
+        Reg::Rsp,
+        Mem {
+            base: Reg::Rbp,
+            disp: -8 * saved,
+        },
+    );
+    for reg in callee_saved.iter().rev() {
+        gen.asm.pop(*reg);
+    }
+    gen.asm.pop(CONTEXT);
+    gen.asm.pop(Reg::Rbp);
+    gen.asm.ret();
+
+    Ok(gen.asm.finish())
+}
+
+/// Compile `func` into executable memory.
+pub fn compile(func: &Function) -> Result<CompiledCode, CompileError> {
+    let code = emit(func)?;
+    let memory = ExecutableMemory::new(&code).map_err(CompileError::Memory)?;
+    Ok(CompiledCode { memory })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+
+    use crate::assembler::assemble;
+    use crate::container::Program;
+    use crate::jit::codegen::{compile, ALLOCATABLE};
+    use crate::jit::lower::lower;
+    use crate::jit::regalloc::Allocation;
+    use crate::verifier::verify;
+    use crate::vm::{Vm, VmError};
+
+    /// Run `program` from its entry in the interpreter only and with code compiled from the entry
+    /// first, the results have to be the same. Returns the VM which ran the compiled code and
+    /// the index the code exited to.
+    fn compare(source: &str) -> (Vm<Vec<u8>>, usize) {
+        let program = assemble("test.S", source).unwrap();
+        verify(&program).unwrap();
+
+        let mut interpreter = Vm::with_output(Vec::new());
+        interpreter.load(program.clone());
+        let expected = interpreter.run();
+
+        let mut vm = Vm::with_output(Vec::new());
+        vm.load(program.clone());
+        let code = compile(&lower(&program.code, vm.pc())).unwrap();
+        unsafe { vm.run_compiled(&code) };
+        let exit = vm.pc();
+        let result = vm.run();
+
+        assert_eq!(result, expected);
+        assert_eq!(
+            String::from_utf8_lossy(vm.output()),
+            String::from_utf8_lossy(interpreter.output())
+        );
+        assert_eq!(vm.acc(), interpreter.acc());
+        for reg in 0..16 {
+            assert_eq!(vm.reg(reg), interpreter.reg(reg), "v{}", reg);
+        }
+        (vm, exit)
+    }
+
+    #[test]
+    fn loops() {
+        let (vm, exit) = compare(include_str!("../../examples/fibonacci.S"));
+        assert_eq!(exit, 13);
+        assert_eq!(
+            String::from_utf8_lossy(vm.output()).lines().last(),
+            Some("13")
+        );
+
+        let (vm, exit) = compare(
+            &include_str!("../../examples/loop_release_37_seconds.S").replace("91615", "300"),
+        );
+        assert_eq!(exit, 7);
+        assert_eq!((vm.reg(1), vm.reg(2)), (Some(0), Some(0)));
+
+        // The phis of v0 and v1 swap their values on the back edge
+        let (vm, _) = compare(
+            "
+            movi v0, 1
+            movi v1, 2
+            movi v2, 5
+            movi v4, 0
+        L:  mov v3, v0
+            mov v0, v1
+            mov v1, v3
+            lda v0
+            print
+            dec v2
+            bne v2, v4, L
+            ",
+        );
+        assert_eq!((vm.reg(0), vm.reg(1)), (Some(2), Some(1)));
+    }
+
+    #[test]
+    fn pressure() {
+        // v0 to v13 are live through the loop and all change in every iteration, the prints
+        // call out in the loop with the values in the registers
+        let mut source = String::from("movi v14, 0\nmovi v15, 12\n");
+        for reg in 0..14 {
+            source.push_str(&format!("movi v{}, {}\n", reg, reg * 7 + 1));
+        }
+        source.push_str("L:\n");
+        for reg in 0..14 {
+            source.push_str(&format!(
+                "lda v{}\nxor v{}\nadd v{}\nsta v{}\n",
+                reg,
+                (reg + 3) % 14,
+                (reg + 5) % 14,
+                reg
+            ));
+        }
+        source.push_str("lda v13\nprint\ndec v15\nbne v15, v14, L\n");
+        for reg in 0..14 {
+            source.push_str(&format!("lda v{}\nprint\n", reg));
+        }
+
+        let program = assemble("test.S", &source).unwrap();
+        let alloc = Allocation::compute(&lower(&program.code, 0), ALLOCATABLE.len());
+        assert!(alloc.stack_slots() > 0);
+        let (vm, _) = compare(&source);
+        assert_eq!(
+            String::from_utf8_lossy(vm.output()).lines().count(),
+            12 + 14
+        );
+    }
+
+    #[test]
+    fn arithmetic() {
+        let (_, exit) = compare(
+            "
+            movi v0, 7
+            movi v1, 3
+            movi v2, 0xffffffff
+            movi v3, 70
+            ldai 100
+            add v0
+            sub v1
+            mul v1
+            div v0
+            mod v1
+            print
+            addi 0xfffffff0
+            subi 5
+            muli 3
+            divi 7
+            modi 1000
+            print
+            neg
+            print
+            not
+            print
+            and v2
+            or v0
+            xor v1
+            print
+            neg
+            sar v0
+            print
+            shr v1
+            print
+            shl v3
+            print
+            ",
+        );
+        assert_eq!(exit, 32);
+    }
+
+    #[test]
+    fn branches() {
+        // Every branch is taken once and falls through once, the prints show which
+        let mut source = String::from(
+            "
+            ldai 1
+            neg
+            sta v0      ; -1
+            movi v1, 1
+            ",
+        );
+        let branches = [
+            "beq", "bne", "blt", "ble", "bgt", "bge", "bltu", "bleu", "bgtu", "bgeu",
+        ];
+        for (i, branch) in branches.iter().enumerate() {
+            for (j, operands) in ["v0, v1", "v1, v0"].iter().enumerate() {
+                let n = 2 * i + j;
+                source.push_str(&format!(
+                    "
+                    {} {}, .L{}
+                    ldai {}
+                    print
+                .L{}:
+                    ",
+                    branch, operands, n, n, n
+                ));
+            }
+        }
+        let (vm, _) = compare(&source);
+        assert_eq!(
+            String::from_utf8_lossy(vm.output()),
+            "0\n1\n5\n7\n8\n10\n12\n14\n17\n19\n"
+        );
+    }
+
+    #[test]
+    fn exits() {
+        // Failing arithmetic leaves the code at the failing instruction
+        for (source, pc) in [
+            (
+                "ldai 0xffffffff\nmuli 0xffffffff\nmuli 0xffffffff\nmuli 2",
+                2,
+            ),
+            ("ldai 5\nmovi v0, 0\nprint\ndiv v0", 3),
+            ("ldai 5\nmodi 0", 1),
+            ("ldai 0\nsubi 1", 1),
+            ("movi v0, 0\ndec v0", 1),
+            ("ldai 2\nmuli 3\ncall f, 0\nprint\nret\nf: ldai 4\nret", 2),
+        ]
+        .iter()
+        {
+            assert_eq!(compare(source).1, *pc, "{}", source);
+        }
+    }
+
+    /// Output failing after `capacity` bytes.
+    struct Limited {
+        written: Vec<u8>,
+        capacity: usize,
+    }
+
+    impl Write for Limited {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.written.len() + buf.len() > self.capacity {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "full"));
+            }
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn output_error() {
+        let program = assemble("test.S", "ldai 7\nprint\nprint\nprint").unwrap();
+        let mut vm = Vm::with_output(Limited {
+            written: Vec::new(),
+            capacity: 4,
+        });
+        vm.load(program);
+        let code = compile(&lower(&vm.program().code, 0)).unwrap();
+        unsafe { vm.run_compiled(&code) };
+
+        assert_eq!(vm.pc(), 3);
+        assert!(matches!(vm.run(), Err(VmError::Output { pc: 3, .. })));
+        assert_eq!(vm.output().written, b"7\n7\n");
+    }
+
+    #[test]
+    fn empty_frame() {
+        // Programs without values and with only an exit
+        let program = Program {
+            code: vec![crate::bytecode::Inst::Ret],
+            ..Program::default()
+        };
+        let mut vm = Vm::with_output(Vec::new());
+        vm.load(program);
+        let code = compile(&lower(&vm.program().code, 0)).unwrap();
+        unsafe { vm.run_compiled(&code) };
+        assert_eq!(vm.pc(), 0);
+    }
+}
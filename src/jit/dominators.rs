@@ -0,0 +1,234 @@
+//! Dominator tree of the blocks of a function.
+//!
+//! The immediate dominators are computed with the iterative algorithm of Cooper, Harvey and
+//! Kennedy, "A Simple, Fast Dominance Algorithm", over the blocks reachable from the entry block
+//! in reverse postorder. The dominance frontiers follow from the immediate dominators as in the
+//! same paper.
+
+use std::collections::{BTreeSet, HashMap};
+
+use super::{Block, Function};
+
+pub struct DominatorTree {
+    /// Reachable blocks in reverse postorder
+    rpo: Vec<Block>,
+    /// Index of every reachable block in `rpo`
+    rpo_number: HashMap<Block, usize>,
+    /// Immediate dominator of every reachable block, the entry block dominates itself
+    idom: HashMap<Block, Block>,
+    /// Blocks immediately dominated by every block
+    children: HashMap<Block, Vec<Block>>,
+    /// Dominance frontier of every reachable block
+    frontiers: HashMap<Block, BTreeSet<Block>>,
+}
+
+/// Empty frontier of blocks which have none.
+static EMPTY: BTreeSet<Block> = BTreeSet::new();
+
+/// Blocks reachable from the entry block of `func` in reverse postorder.
+fn reverse_postorder(func: &Function) -> Vec<Block> {
+    let entry = match func.entry_block() {
+        Some(entry) => entry,
+        None => return Vec::new(),
+    };
+    let mut postorder = Vec::new();
+    let mut visited = vec![entry];
+    // Blocks on the path from the entry with their unvisited successors
+    let mut stack = vec![(entry, func.succs(entry).collect::<Vec<_>>())];
+    while let Some((block, succs)) = stack.last_mut() {
+        match succs.pop() {
+            Some(succ) if !visited.contains(&succ) => {
+                visited.push(succ);
+                stack.push((succ, func.succs(succ).collect()));
+            }
+            Some(_) => (),
+            None => {
+                postorder.push(*block);
+                stack.pop();
+            }
+        }
+    }
+    postorder.reverse();
+    postorder
+}
+
+impl DominatorTree {
+    pub fn compute(func: &Function) -> Self {
+        let rpo = reverse_postorder(func);
+        let rpo_number: HashMap<Block, usize> = rpo
+            .iter()
+            .enumerate()
+            .map(|(i, block)| (*block, i))
+            .collect();
+        let mut tree = Self {
+            idom: HashMap::new(),
+            children: HashMap::new(),
+            frontiers: HashMap::new(),
+            rpo_number,
+            rpo,
+        };
+        let entry = match tree.rpo.first() {
+            Some(entry) => *entry,
+            None => return tree,
+        };
+        tree.idom.insert(entry, entry);
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in tree.rpo[1..].iter().copied() {
+                let mut new_idom = None;
+                for pred in func.preds(block) {
+                    if !tree.idom.contains_key(&pred) {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        Some(idom) => tree.intersect(pred, idom),
+                        None => pred,
+                    });
+                }
+                let new_idom = new_idom.unwrap();
+                if tree.idom.get(&block) != Some(&new_idom) {
+                    tree.idom.insert(block, new_idom);
+                    changed = true;
+                }
+            }
+        }
+
+        for i in 0..tree.rpo.len() {
+            let block = tree.rpo[i];
+            let idom = tree.idom(block);
+            if let Some(idom) = idom {
+                tree.children.entry(idom).or_default().push(block);
+            }
+
+            // The block is in the frontier of the dominators of its predecessors up to its own
+            // immediate dominator, all of them for the entry block
+            for pred in func.preds(block) {
+                if !tree.is_reachable(pred) {
+                    continue;
+                }
+                let mut runner = Some(pred);
+                while runner != idom {
+                    let dominator = runner.unwrap();
+                    tree.frontiers.entry(dominator).or_default().insert(block);
+                    runner = tree.idom(dominator);
+                }
+            }
+        }
+        tree
+    }
+
+    /// Nearest common dominator of `a` and `b`.
+    fn intersect(&self, mut a: Block, mut b: Block) -> Block {
+        while a != b {
+            while self.rpo_number[&a] > self.rpo_number[&b] {
+                a = self.idom[&a];
+            }
+            while self.rpo_number[&b] > self.rpo_number[&a] {
+                b = self.idom[&b];
+            }
+        }
+        a
+    }
+
+    /// Reachable blocks in reverse postorder, starting with the entry block.
+    pub fn rpo(&self) -> &[Block] {
+        &self.rpo
+    }
+
+    pub fn is_reachable(&self, block: Block) -> bool {
+        self.rpo_number.contains_key(&block)
+    }
+
+    /// Immediate dominator of `block`, `None` for the entry block and unreachable blocks.
+    pub fn idom(&self, block: Block) -> Option<Block> {
+        self.idom.get(&block).copied().filter(|idom| *idom != block)
+    }
+
+    /// Blocks immediately dominated by `block` in reverse postorder.
+    pub fn children(&self, block: Block) -> &[Block] {
+        self.children.get(&block).map_or(&[], Vec::as_slice)
+    }
+
+    /// Blocks where the dominance of `block` ends: they are not strictly dominated by `block` but
+    /// one of their predecessors is dominated by it.
+    pub fn frontier(&self, block: Block) -> &BTreeSet<Block> {
+        self.frontiers.get(&block).unwrap_or(&EMPTY)
+    }
+
+    /// Nearest block dominating both `a` and `b`, `None` if one of them is unreachable.
+    pub fn common_dominator(&self, a: Block, b: Block) -> Option<Block> {
+        if self.is_reachable(a) && self.is_reachable(b) {
+            Some(self.intersect(a, b))
+        } else {
+            None
+        }
+    }
+
+    /// Does `a` strictly dominate `b`, that is dominate it without being `b`?
+    pub fn strictly_dominates(&self, a: Block, b: Block) -> bool {
+        a != b && self.dominates(a, b)
+    }
+
+    /// Does `a` dominate `b`? Every block dominates itself and unreachable blocks are not
+    /// dominated.
+    pub fn dominates(&self, a: Block, mut b: Block) -> bool {
+        let a_number = match self.rpo_number.get(&a) {
+            Some(number) => *number,
+            None => return false,
+        };
+        if !self.is_reachable(b) {
+            return false;
+        }
+        while self.rpo_number[&b] > a_number {
+            b = self.idom[&b];
+        }
+        a == b
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jit::dominators::DominatorTree;
+    use crate::jit::text::parse_function;
+
+    #[test]
+    fn diamond() {
+        // block1 branches around block2 and block3 which join at block4, block5 is unreachable
+        let func = parse_function(
+            "function {
+            block0:
+                v1 = load r0
+                jump block1
+            block1:
+                beq v1, v1, block2, block3
+            block2:
+                jump block4
+            block3:
+                bne v1, v1, block3, block4
+            block4:
+                exit 0
+            block5:
+                jump block4
+            }",
+        )
+        .unwrap();
+        let domtree = DominatorTree::compute(&func);
+
+        assert_eq!(domtree.rpo()[..2], [0, 1]);
+        assert_eq!(domtree.rpo().len(), 5);
+        assert_eq!(domtree.idom(0), None);
+        assert_eq!(domtree.idom(2), Some(1));
+        assert_eq!(domtree.idom(3), Some(1));
+        assert_eq!(domtree.idom(4), Some(1));
+        assert_eq!(domtree.idom(5), None);
+
+        assert!(domtree.dominates(1, 4));
+        assert!(domtree.dominates(3, 3));
+        assert!(!domtree.dominates(2, 4));
+        assert!(!domtree.dominates(3, 4));
+        assert!(!domtree.dominates(0, 5));
+        assert!(!domtree.is_reachable(5));
+    }
+}
@@ -0,0 +1,168 @@
+//! Graphviz DOT export of the control-flow graphs.
+//!
+//! Every block is a node listing its instructions and the edges of conditional branches are
+//! labeled `taken` and `fallthrough`. The output can be rendered with `dot -Tsvg`.
+
+use std::fmt::Write;
+
+use super::cfg::{ControlFlowGraph, EdgeKind};
+use super::{Function, InstData};
+use crate::bytecode::Inst;
+use crate::disassembler::operands;
+
+/// Start of a graph named `name` with boxes for the blocks.
+fn header(name: &str) -> String {
+    format!(
+        "digraph {} {{\n    node [shape=box, fontname=monospace];\n",
+        name
+    )
+}
+
+/// Node of `block` whose label has left-justified `lines`.
+fn node(out: &mut String, block: u32, lines: &[String]) {
+    let mut label = format!("block{}:\\l", block);
+    for line in lines {
+        label.push_str(&line.replace('\\', "\\\\").replace('"', "\\\""));
+        label.push_str("\\l");
+    }
+    writeln!(out, "    block{} [label=\"{}\"];", block, label).unwrap();
+}
+
+fn edge(out: &mut String, from: u32, to: u32, label: Option<&str>) {
+    match label {
+        Some(label) => writeln!(
+            out,
+            "    block{} -> block{} [label=\"{}\"];",
+            from, to, label
+        ),
+        None => writeln!(out, "    block{} -> block{};", from, to),
+    }
+    .unwrap();
+}
+
+/// Graph of the bytecode blocks of `cfg` built from `code`. The instructions are listed with
+/// their indices and branch and call targets are indices as well.
+pub fn cfg_to_dot(code: &[Inst], cfg: &ControlFlowGraph) -> String {
+    let mut out = header("bytecode");
+    for block in cfg.blocks() {
+        let lines: Vec<String> = cfg
+            .insts(block)
+            .map(|pc| {
+                let inst = code[pc];
+                let target = match inst {
+                    Inst::Call(target, _) => target.to_string(),
+                    _ => inst
+                        .branch_target()
+                        .map(|t| t.to_string())
+                        .unwrap_or_default(),
+                };
+                let text = format!("{}: {} {}", pc, inst.mnemonic(), operands(inst, &target));
+                text.trim_end().to_string()
+            })
+            .collect();
+        node(&mut out, block, &lines);
+    }
+    for e in cfg.edges() {
+        let label = match e.kind {
+            EdgeKind::Taken => "taken",
+            EdgeKind::Fallthrough => "fallthrough",
+        };
+        edge(&mut out, e.from, e.to, Some(label));
+    }
+    out.push_str("}\n");
+    out
+}
+
+/// Graph of the blocks of `func` in layout order with their instructions in the textual IR.
+/// Conditional branches continue with their second successor when they are not taken.
+pub fn function_to_dot(func: &Function) -> String {
+    let mut out = header("ssa");
+    let layout = func.layout();
+    for block in layout.blocks() {
+        let lines: Vec<String> = layout
+            .block_insts(block)
+            .map(|inst| func.dfg().display_inst(inst).to_string())
+            .collect();
+        node(&mut out, block, &lines);
+    }
+    for block in layout.blocks() {
+        let last = match layout.last_inst(block) {
+            Some(last) => last,
+            None => continue,
+        };
+        match func.dfg().inst_data(last) {
+            InstData::Branch { succs, .. } => {
+                edge(&mut out, block, succs[0], Some("taken"));
+                edge(&mut out, block, succs[1], Some("fallthrough"));
+            }
+            InstData::Jump { dest, .. } => edge(&mut out, block, *dest, None),
+            _ => (),
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::Inst;
+    use crate::jit::cfg::build_cfg;
+    use crate::jit::dot::{cfg_to_dot, function_to_dot};
+    use crate::jit::text::parse_function;
+
+    #[test]
+    fn bytecode() {
+        let code = vec![
+            Inst::Movi(0, 3),
+            Inst::Dec(0),
+            Inst::Call(4, 0),
+            Inst::Bne(0, 1, 1),
+            Inst::Ret,
+        ];
+
+        assert_eq!(
+            cfg_to_dot(&code, &build_cfg(&code)),
+            r#"digraph bytecode {
+    node [shape=box, fontname=monospace];
+    block0 [label="block0:\l0: movi v0, 3\l"];
+    block1 [label="block1:\l1: dec v0\l2: call 4, 0\l3: bne v0, v1, 1\l"];
+    block2 [label="block2:\l4: ret\l"];
+    block0 -> block1 [label="fallthrough"];
+    block1 -> block1 [label="taken"];
+    block1 -> block2 [label="fallthrough"];
+}
+"#
+        );
+    }
+
+    #[test]
+    fn ssa() {
+        let func = parse_function(
+            "function {
+            block0:
+                v1 = load r0
+                jump block1
+            block1:
+                v2 = phi v1, v2
+                beq v2, v2, block1, block2
+            block2:
+                exit 4
+            }",
+        )
+        .unwrap();
+
+        assert_eq!(
+            function_to_dot(&func),
+            r#"digraph ssa {
+    node [shape=box, fontname=monospace];
+    block0 [label="block0:\lv1 = load r0\ljump block1\l"];
+    block1 [label="block1:\lv2 = phi v1, v2\lbeq v2, v2, block1, block2\l"];
+    block2 [label="block2:\lexit 4\l"];
+    block0 -> block1;
+    block1 -> block1 [label="taken"];
+    block1 -> block2 [label="fallthrough"];
+}
+"#
+        );
+    }
+}
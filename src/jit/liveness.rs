@@ -0,0 +1,134 @@
+//! Values live at the boundaries of the blocks of a function.
+//!
+//! A phi uses its inputs at the end of the corresponding predecessors rather than in its own
+//! block, so the inputs are live out of the predecessors while the phi is defined at the start of
+//! its block. The sets are computed by iterating the data flow equations backwards over the
+//! layout until they no longer change.
+
+use std::collections::{BTreeSet, HashMap};
+
+use super::{Block, Function, Inst, InstData};
+
+pub struct Liveness {
+    live_in: HashMap<Block, BTreeSet<Inst>>,
+    live_out: HashMap<Block, BTreeSet<Inst>>,
+}
+
+/// Empty set of blocks without live values.
+static EMPTY: BTreeSet<Inst> = BTreeSet::new();
+
+/// Phis of `block` with their inputs coming from `pred`.
+pub fn phi_inputs(func: &Function, pred: Block, block: Block) -> Vec<(Inst, Inst)> {
+    let index = match func.preds(block).position(|p| p == pred) {
+        Some(index) => index,
+        None => return Vec::new(),
+    };
+    func.layout
+        .block_insts(block)
+        .filter_map(|inst| match func.dfg.inst_data(inst) {
+            InstData::Phi { inputs, .. } => Some((inst, inputs[index])),
+            _ => None,
+        })
+        .collect()
+}
+
+impl Liveness {
+    pub fn compute(func: &Function) -> Self {
+        let blocks: Vec<Block> = func.layout.blocks().collect();
+
+        // Values used in every block before being defined there and values defined there
+        let mut uses: HashMap<Block, BTreeSet<Inst>> = HashMap::new();
+        let mut defs: HashMap<Block, BTreeSet<Inst>> = HashMap::new();
+        for block in blocks.iter().copied() {
+            let mut block_uses = BTreeSet::new();
+            let mut block_defs = BTreeSet::new();
+            for inst in func.layout.block_insts(block) {
+                let data = func.dfg.inst_data(inst);
+                if !matches!(data, InstData::Phi { .. }) {
+                    block_uses.extend(
+                        data.inputs()
+                            .iter()
+                            .filter(|input| !block_defs.contains(*input)),
+                    );
+                }
+                if data.has_result() {
+                    block_defs.insert(inst);
+                }
+            }
+            uses.insert(block, block_uses);
+            defs.insert(block, block_defs);
+        }
+
+        let mut liveness = Self {
+            live_in: HashMap::new(),
+            live_out: HashMap::new(),
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in blocks.iter().rev().copied() {
+                let mut live_out = BTreeSet::new();
+                for succ in func.succs(block) {
+                    live_out.extend(liveness.live_in(succ));
+                    live_out.extend(phi_inputs(func, block, succ).iter().map(|(_, input)| input));
+                }
+
+                let mut live_in = uses[&block].clone();
+                live_in.extend(live_out.difference(&defs[&block]));
+                if liveness.live_in(block) != &live_in {
+                    liveness.live_in.insert(block, live_in);
+                    changed = true;
+                }
+                liveness.live_out.insert(block, live_out);
+            }
+        }
+        liveness
+    }
+
+    /// Values live at the start of `block`, its phis are defined there and not included.
+    pub fn live_in(&self, block: Block) -> &BTreeSet<Inst> {
+        self.live_in.get(&block).unwrap_or(&EMPTY)
+    }
+
+    /// Values live at the end of `block` including the inputs of the phis of its successors.
+    pub fn live_out(&self, block: Block) -> &BTreeSet<Inst> {
+        self.live_out.get(&block).unwrap_or(&EMPTY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use crate::assembler::assemble;
+    use crate::jit::liveness::Liveness;
+    use crate::jit::lower::lower;
+
+    fn set(values: &[u32]) -> BTreeSet<u32> {
+        values.iter().copied().collect()
+    }
+
+    #[test]
+    fn loops() {
+        let program = assemble(
+            "loop_release_37_seconds.S",
+            include_str!("../../examples/loop_release_37_seconds.S"),
+        )
+        .unwrap();
+        // v1 is the zero the loops compare with, v8 the outer counter, the phis v6 and v13 are
+        // the counters at the loop headers block2 and block3 and v15 the inner counter
+        let func = lower(&program.code, 0);
+        let liveness = Liveness::compute(&func);
+
+        assert!(liveness.live_in(1).is_empty());
+        assert_eq!(liveness.live_out(1), &set(&[1, 3]));
+        assert_eq!(liveness.live_in(2), &set(&[1]));
+        assert_eq!(liveness.live_out(2), &set(&[1, 8, 10]));
+        assert_eq!(liveness.live_in(3), &set(&[1, 8]));
+        assert_eq!(liveness.live_out(3), &set(&[1, 8, 15]));
+        assert_eq!(liveness.live_in(4), &set(&[1, 8]));
+        assert_eq!(liveness.live_out(4), &set(&[1, 8]));
+        assert!(liveness.live_in(5).is_empty());
+        assert!(liveness.live_out(5).is_empty());
+    }
+}
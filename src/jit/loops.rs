@@ -0,0 +1,249 @@
+//! Natural loops of a function and how they nest.
+//!
+//! An edge is a back edge when its destination, the loop header, dominates its source. The loop of
+//! a header consists of the blocks which reach one of its back edges without passing through the
+//! header, back edges to the same header form one loop. Loops are either disjoint or nested since
+//! a loop containing the header of another loop contains all of its blocks.
+
+use std::collections::{BTreeSet, HashMap};
+
+use super::dominators::DominatorTree;
+use super::{Block, Function};
+
+/// Number of a loop, loops are numbered by the reverse postorder of their headers so outer loops
+/// come before the loops nested in them.
+pub type Loop = u32;
+
+struct LoopData {
+    header: Block,
+    /// Sources of the back edges
+    latches: BTreeSet<Block>,
+    blocks: BTreeSet<Block>,
+    /// Edges from blocks of the loop to blocks outside of it
+    exits: Vec<(Block, Block)>,
+    /// Innermost loop containing this one
+    parent: Option<Loop>,
+    /// 1 for outermost loops
+    depth: usize,
+}
+
+pub struct LoopAnalysis {
+    loops: Vec<LoopData>,
+    /// Innermost loop of every block in a loop
+    innermost: HashMap<Block, Loop>,
+}
+
+impl LoopAnalysis {
+    pub fn compute(func: &Function, domtree: &DominatorTree) -> Self {
+        let mut analysis = Self {
+            loops: Vec::new(),
+            innermost: HashMap::new(),
+        };
+
+        for header in domtree.rpo().iter().copied() {
+            let latches: BTreeSet<Block> = func
+                .preds(header)
+                .filter(|pred| domtree.dominates(header, *pred))
+                .collect();
+            if latches.is_empty() {
+                continue;
+            }
+
+            // Walk backwards from the latches, the header dominates every block reaching them
+            // so the walk stays in the loop
+            let mut blocks: BTreeSet<Block> = BTreeSet::new();
+            blocks.insert(header);
+            let mut worklist: Vec<Block> = latches.iter().copied().collect();
+            while let Some(block) = worklist.pop() {
+                if blocks.insert(block) {
+                    worklist.extend(func.preds(block).filter(|p| domtree.is_reachable(*p)));
+                }
+            }
+
+            // Loops containing the header are processed already, the innermost one comes last
+            let parent = analysis
+                .loops
+                .iter()
+                .rposition(|outer| outer.blocks.contains(&header))
+                .map(|outer| outer as Loop);
+            let depth = parent.map_or(1, |outer| analysis.loops[outer as usize].depth + 1);
+            let exits = blocks
+                .iter()
+                .flat_map(|block| func.succs(*block).map(move |succ| (*block, succ)))
+                .filter(|(_, succ)| !blocks.contains(succ))
+                .collect();
+            let lp = analysis.loops.len() as Loop;
+            for block in &blocks {
+                analysis.innermost.insert(*block, lp);
+            }
+            analysis.loops.push(LoopData {
+                header,
+                latches,
+                blocks,
+                exits,
+                parent,
+                depth,
+            });
+        }
+        analysis
+    }
+
+    /// Iterate over the loops, outer loops come before the loops they contain.
+    pub fn loops(&self) -> impl Iterator<Item = Loop> {
+        0..self.loops.len() as Loop
+    }
+
+    pub fn header(&self, lp: Loop) -> Block {
+        self.loops[lp as usize].header
+    }
+
+    /// Blocks of the loop including the header and the blocks of nested loops.
+    pub fn blocks(&self, lp: Loop) -> &BTreeSet<Block> {
+        &self.loops[lp as usize].blocks
+    }
+
+    /// Blocks branching back to the header.
+    pub fn latches(&self, lp: Loop) -> &BTreeSet<Block> {
+        &self.loops[lp as usize].latches
+    }
+
+    /// Back edges of the loop from the latches to the header.
+    pub fn back_edges(&self, lp: Loop) -> impl Iterator<Item = (Block, Block)> + '_ {
+        let header = self.header(lp);
+        self.latches(lp).iter().map(move |latch| (*latch, header))
+    }
+
+    /// Edges leaving the loop, from blocks of the loop to blocks outside of it.
+    pub fn exits(&self, lp: Loop) -> &[(Block, Block)] {
+        &self.loops[lp as usize].exits
+    }
+
+    /// Innermost loop containing `lp`.
+    pub fn parent(&self, lp: Loop) -> Option<Loop> {
+        self.loops[lp as usize].parent
+    }
+
+    /// Number of loops `lp` is nested in including itself.
+    pub fn depth(&self, lp: Loop) -> usize {
+        self.loops[lp as usize].depth
+    }
+
+    /// Innermost loop containing `block`.
+    pub fn innermost_loop(&self, block: Block) -> Option<Loop> {
+        self.innermost.get(&block).copied()
+    }
+
+    /// Number of loops containing `block`, 0 outside of loops.
+    pub fn loop_depth(&self, block: Block) -> usize {
+        self.innermost_loop(block).map_or(0, |lp| self.depth(lp))
+    }
+
+    pub fn is_header(&self, block: Block) -> bool {
+        self.innermost_loop(block)
+            .is_some_and(|lp| self.header(lp) == block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use crate::assembler::assemble;
+    use crate::jit::dominators::DominatorTree;
+    use crate::jit::loops::LoopAnalysis;
+    use crate::jit::lower::lower;
+    use crate::jit::text::parse_function;
+
+    fn set(blocks: &[u32]) -> BTreeSet<u32> {
+        blocks.iter().copied().collect()
+    }
+
+    #[test]
+    fn nested() {
+        let program = assemble(
+            "loop_release_37_seconds.S",
+            include_str!("../../examples/loop_release_37_seconds.S"),
+        )
+        .unwrap();
+        // block1 initializes the counters, block2 is L2, block3 is L1, block4 is the last
+        // branch and block5 leaves the loops
+        let func = lower(&program.code, 0);
+        let domtree = DominatorTree::compute(&func);
+
+        assert_eq!(domtree.rpo(), &[0, 1, 2, 3, 4, 5]);
+        assert_eq!(domtree.idom(3), Some(2));
+        assert_eq!(domtree.children(2), &[3]);
+        assert_eq!(domtree.children(3), &[4]);
+        assert_eq!(domtree.frontier(2), &set(&[2]));
+        assert_eq!(domtree.frontier(3), &set(&[2, 3]));
+        assert_eq!(domtree.frontier(4), &set(&[2]));
+        assert!(domtree.frontier(5).is_empty());
+        assert_eq!(domtree.common_dominator(3, 5), Some(3));
+        assert!(domtree.strictly_dominates(2, 4));
+        assert!(!domtree.strictly_dominates(4, 4));
+
+        let loops = LoopAnalysis::compute(&func, &domtree);
+        assert_eq!(loops.loops().count(), 2);
+        let (outer, inner) = (0, 1);
+        assert_eq!(loops.header(outer), 2);
+        assert_eq!(loops.blocks(outer), &set(&[2, 3, 4]));
+        assert_eq!(loops.back_edges(outer).collect::<Vec<_>>(), vec![(4, 2)]);
+        assert_eq!(loops.exits(outer), &[(4, 5)]);
+        assert_eq!(loops.parent(outer), None);
+        assert_eq!(loops.depth(outer), 1);
+
+        assert_eq!(loops.header(inner), 3);
+        assert_eq!(loops.blocks(inner), &set(&[3]));
+        assert_eq!(loops.back_edges(inner).collect::<Vec<_>>(), vec![(3, 3)]);
+        assert_eq!(loops.exits(inner), &[(3, 4)]);
+        assert_eq!(loops.parent(inner), Some(outer));
+        assert_eq!(loops.depth(inner), 2);
+
+        assert_eq!(loops.innermost_loop(4), Some(outer));
+        assert_eq!(loops.innermost_loop(1), None);
+        assert_eq!(
+            (1..=5).map(|b| loops.loop_depth(b)).collect::<Vec<_>>(),
+            vec![0, 1, 2, 1, 0]
+        );
+        assert!(loops.is_header(3));
+        assert!(!loops.is_header(4));
+    }
+
+    #[test]
+    fn shared_header() {
+        // Two back edges to block1 make one loop, block3 exits it and the entry block is the
+        // header of an outer loop
+        let func = parse_function(
+            "function {
+            block0:
+                v1 = load r0
+                jump block1
+            block1:
+                beq v1, v1, block2, block3
+            block2:
+                bne v1, v1, block1, block1
+            block3:
+                blt v1, v1, block1, block4
+            block4:
+                bgt v1, v1, block0, block5
+            block5:
+                exit 0
+            }",
+        )
+        .unwrap();
+        let domtree = DominatorTree::compute(&func);
+        let loops = LoopAnalysis::compute(&func, &domtree);
+
+        assert_eq!(domtree.frontier(4), &set(&[0]));
+        assert_eq!(domtree.frontier(0), &set(&[0]));
+        assert_eq!(loops.loops().count(), 2);
+        assert_eq!(loops.header(0), 0);
+        assert_eq!(loops.blocks(0), &set(&[0, 1, 2, 3, 4]));
+        assert_eq!(loops.exits(0), &[(4, 5)]);
+        assert_eq!(loops.header(1), 1);
+        assert_eq!(loops.latches(1), &set(&[2, 3]));
+        assert_eq!(loops.exits(1), &[(3, 4)]);
+        assert_eq!(loops.parent(1), Some(0));
+        assert_eq!(loops.loop_depth(2), 2);
+    }
+}
@@ -0,0 +1,743 @@
+//! Translation of bytecode into SSA form.
+//!
+//! The SSA values are built with the algorithm of Braun et al., "Simple and Efficient
+//! Construction of Static Single Assignment Form": registers and the accumulator are read through
+//! `read_var` which looks for their definition in the block and then in its predecessors and
+//! creates phis at join points, trivial phis are removed again.
+//!
+//! A function starts with a synthetic entry block which loads the registers and the accumulator
+//! read before being written and jumps to the block of the entry instruction. Every definition is
+//! also stored to the interpreter state, so the interpreter can take over at any instruction:
+//! calls, returns, failing arithmetic and branches out of the code exit to it.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use super::cfg::build_cfg;
+use super::verifier::debug_verify;
+use super::{Block, Function, Inst, InstData, Opcode, Var};
+use crate::bytecode;
+
+/// Where control goes when it leaves the instructions of a block.
+#[derive(Clone, Copy)]
+enum Target {
+    /// The block starting at the instruction.
+    Block(usize),
+    /// Leave to the interpreter at the instruction.
+    Exit(u32),
+}
+
+/// The way a bytecode block ends in the region.
+enum Tail {
+    Jump(Target),
+    Branch(Opcode, [u8; 2], [Target; 2]),
+}
+
+fn target(code: &[bytecode::Inst], pc: usize) -> Target {
+    if pc < code.len() {
+        Target::Block(pc)
+    } else {
+        Target::Exit(pc as u32)
+    }
+}
+
+/// Opcode of the conditional branch `inst` and its register operands.
+fn cond_branch(inst: bytecode::Inst) -> Option<(Opcode, [u8; 2])> {
+    let (opcode, v1, v2) = match inst {
+        bytecode::Inst::Beq(v1, v2, _) => (Opcode::Beq, v1, v2),
+        bytecode::Inst::Bne(v1, v2, _) => (Opcode::Bne, v1, v2),
+        bytecode::Inst::Blt(v1, v2, _) => (Opcode::Blt, v1, v2),
+        bytecode::Inst::Ble(v1, v2, _) => (Opcode::Ble, v1, v2),
+        bytecode::Inst::Bgt(v1, v2, _) => (Opcode::Bgt, v1, v2),
+        bytecode::Inst::Bge(v1, v2, _) => (Opcode::Bge, v1, v2),
+        bytecode::Inst::Bltu(v1, v2, _) => (Opcode::Bltu, v1, v2),
+        bytecode::Inst::Bleu(v1, v2, _) => (Opcode::Bleu, v1, v2),
+        bytecode::Inst::Bgtu(v1, v2, _) => (Opcode::Bgtu, v1, v2),
+        bytecode::Inst::Bgeu(v1, v2, _) => (Opcode::Bgeu, v1, v2),
+        _ => return None,
+    };
+    Some((opcode, [v1, v2]))
+}
+
+/// Instructions `start..end` of a bytecode block lowered up to the instruction which leaves the
+/// block: calls and returns exit to the interpreter which executes them.
+fn split_block(code: &[bytecode::Inst], start: usize, end: usize) -> (usize, Tail) {
+    let call = code[start..end]
+        .iter()
+        .position(|inst| matches!(inst, bytecode::Inst::Call(..) | bytecode::Inst::Ret));
+    if let Some(offset) = call {
+        let pc = start + offset;
+        return (pc, Tail::Jump(Target::Exit(pc as u32)));
+    }
+
+    let last = code[end - 1];
+    if let bytecode::Inst::Jmp(dest) = last {
+        (end - 1, Tail::Jump(target(code, dest as usize)))
+    } else if let Some((opcode, regs)) = cond_branch(last) {
+        let taken = target(code, last.branch_target().unwrap() as usize);
+        (
+            end - 1,
+            Tail::Branch(opcode, regs, [taken, target(code, end)]),
+        )
+    } else {
+        (end, Tail::Jump(target(code, end)))
+    }
+}
+
+struct Builder<'a> {
+    code: &'a [bytecode::Inst],
+    func: Function,
+    /// The synthetic entry block
+    entry: Block,
+    /// Definition of every variable at the end of the blocks
+    defs: HashMap<(Var, Block), Inst>,
+    /// Phis of blocks with unknown predecessors, they get their inputs when the block is sealed
+    incomplete_phis: HashMap<Block, Vec<(Var, Inst)>>,
+    sealed: HashSet<Block>,
+    filled: HashSet<Block>,
+    /// Values of removed trivial phis
+    replaced: HashMap<Inst, Inst>,
+}
+
+impl<'a> Builder<'a> {
+    fn append(&mut self, block: Block, data: InstData) -> Inst {
+        let inst = self.func.dfg.make_inst(data);
+        self.func.layout.append_inst(inst, block);
+        inst
+    }
+
+    fn constant(&mut self, block: Block, value: u32) -> Inst {
+        self.append(
+            block,
+            InstData::Constant {
+                opcode: Opcode::Constant,
+                value: value as u64,
+            },
+        )
+    }
+
+    /// Define `var` in `block` and store it to the interpreter state.
+    fn define(&mut self, var: Var, block: Block, value: Inst) {
+        self.defs.insert((var, block), value);
+        self.append(
+            block,
+            InstData::Store {
+                opcode: Opcode::Store,
+                var,
+                input: value,
+            },
+        );
+    }
+
+    fn resolve(&self, mut value: Inst) -> Inst {
+        while let Some(new) = self.replaced.get(&value) {
+            value = *new;
+        }
+        value
+    }
+
+    fn read_var(&mut self, var: Var, block: Block) -> Inst {
+        if let Some(value) = self.defs.get(&(var, block)) {
+            return self.resolve(*value);
+        }
+
+        let preds: Vec<Block> = self.func.preds(block).collect();
+        let value = if block == self.entry {
+            self.append(
+                block,
+                InstData::Load {
+                    opcode: Opcode::Load,
+                    var,
+                },
+            )
+        } else if !self.sealed.contains(&block) {
+            let phi = self.new_phi(block);
+            self.incomplete_phis
+                .entry(block)
+                .or_default()
+                .push((var, phi));
+            phi
+        } else if preds.len() == 1 {
+            self.read_var(var, preds[0])
+        } else {
+            // Break cycles through loops with a phi without inputs
+            let phi = self.new_phi(block);
+            self.defs.insert((var, block), phi);
+            self.add_phi_inputs(var, phi)
+        };
+        self.defs.insert((var, block), value);
+        value
+    }
+
+    fn new_phi(&mut self, block: Block) -> Inst {
+        let phi = self.func.dfg.make_inst(InstData::Phi {
+            opcode: Opcode::Phi,
+            inputs: Vec::new(),
+        });
+        self.func.layout.prepend_inst(phi, block);
+        phi
+    }
+
+    fn add_phi_inputs(&mut self, var: Var, phi: Inst) -> Inst {
+        let block = self.func.layout.inst_block(phi).unwrap();
+        let preds: Vec<Block> = self.func.preds(block).collect();
+        for pred in preds {
+            let input = self.read_var(var, pred);
+            self.func.dfg.push_phi_input(phi, input);
+        }
+        self.try_remove_trivial_phi(phi)
+    }
+
+    /// Replace `phi` by its only input other than itself if there is one.
+    fn try_remove_trivial_phi(&mut self, phi: Inst) -> Inst {
+        let mut same = None;
+        for input in self.func.dfg.inst_data(phi).inputs() {
+            if Some(*input) == same || *input == phi {
+                continue;
+            }
+            if same.is_some() {
+                return phi;
+            }
+            same = Some(*input);
+        }
+        let same = match same {
+            Some(same) => same,
+            // The phi is only reachable from itself
+            None => return phi,
+        };
+
+        let users: Vec<Inst> = self
+            .func
+            .dfg
+            .users(phi)
+            .iter()
+            .copied()
+            .filter(|user| *user != phi)
+            .collect();
+        self.func.dfg.replace_uses(phi, same);
+        self.func.layout.remove_inst(phi);
+        self.func.dfg.remove_inst(phi);
+        self.replaced.insert(phi, same);
+
+        for user in users {
+            let user = self.resolve(user);
+            if let Some(InstData::Phi { .. }) = self.func.dfg.insts.get(&user) {
+                self.try_remove_trivial_phi(user);
+            }
+        }
+        self.resolve(same)
+    }
+
+    fn seal(&mut self, block: Block) {
+        for (var, phi) in self.incomplete_phis.remove(&block).unwrap_or_default() {
+            self.add_phi_inputs(var, phi);
+        }
+        self.sealed.insert(block);
+    }
+
+    /// Seal `block` if all its predecessors are filled.
+    fn try_seal(&mut self, block: Block) {
+        if !self.sealed.contains(&block) && self.func.preds(block).all(|p| self.filled.contains(&p))
+        {
+            self.seal(block);
+        }
+    }
+
+    /// Lower the bytecode instructions `start..end` into `block`.
+    fn fill(&mut self, block: Block, start: usize, end: usize) {
+        use bytecode::Inst as B;
+
+        for pc in start..end {
+            let exit = pc as u32;
+            match self.code[pc] {
+                B::Mov(v1, v2) => {
+                    let value = self.read_var(Var::Reg(v2), block);
+                    self.define(Var::Reg(v1), block, value);
+                }
+                B::Movi(v, imm) => {
+                    let value = self.constant(block, imm);
+                    self.define(Var::Reg(v), block, value);
+                }
+                B::Ldai(imm) => {
+                    let value = self.constant(block, imm);
+                    self.define(Var::Acc, block, value);
+                }
+                B::Lda(v) => {
+                    let value = self.read_var(Var::Reg(v), block);
+                    self.define(Var::Acc, block, value);
+                }
+                B::Sta(v) => {
+                    let value = self.read_var(Var::Acc, block);
+                    self.define(Var::Reg(v), block, value);
+                }
+                B::Add(v) => self.acc_reg(block, Opcode::Add, v, Some(exit)),
+                B::Sub(v) => self.acc_reg(block, Opcode::Sub, v, Some(exit)),
+                B::Mul(v) => self.acc_reg(block, Opcode::Mul, v, Some(exit)),
+                B::Div(v) => self.acc_reg(block, Opcode::Div, v, Some(exit)),
+                B::Mod(v) => self.acc_reg(block, Opcode::Mod, v, Some(exit)),
+                B::And(v) => self.acc_reg(block, Opcode::And, v, None),
+                B::Or(v) => self.acc_reg(block, Opcode::Or, v, None),
+                B::Xor(v) => self.acc_reg(block, Opcode::Xor, v, None),
+                B::Shl(v) => self.acc_reg(block, Opcode::Shl, v, None),
+                B::Shr(v) => self.acc_reg(block, Opcode::Shr, v, None),
+                B::Sar(v) => self.acc_reg(block, Opcode::Sar, v, None),
+                B::Addi(imm) => self.acc_imm(block, Opcode::Add, imm, exit),
+                B::Subi(imm) => self.acc_imm(block, Opcode::Sub, imm, exit),
+                B::Muli(imm) => self.acc_imm(block, Opcode::Mul, imm, exit),
+                B::Divi(imm) => self.acc_imm(block, Opcode::Div, imm, exit),
+                B::Modi(imm) => self.acc_imm(block, Opcode::Mod, imm, exit),
+                B::Dec(v) => {
+                    let value = self.read_var(Var::Reg(v), block);
+                    let one = self.constant(block, 1);
+                    let result = self.append(
+                        block,
+                        InstData::Checked {
+                            opcode: Opcode::Sub,
+                            inputs: [value, one],
+                            exit,
+                        },
+                    );
+                    self.define(Var::Reg(v), block, result);
+                }
+                B::Neg | B::Not => {
+                    let opcode = match self.code[pc] {
+                        B::Neg => Opcode::Neg,
+                        _ => Opcode::Not,
+                    };
+                    let input = self.read_var(Var::Acc, block);
+                    let result = self.append(block, InstData::Unary { opcode, input });
+                    self.define(Var::Acc, block, result);
+                }
+                B::Print => {
+                    let input = self.read_var(Var::Acc, block);
+                    self.append(
+                        block,
+                        InstData::Print {
+                            opcode: Opcode::Print,
+                            input,
+                            exit,
+                        },
+                    );
+                }
+                inst => unreachable!("{:?} ends a block", inst),
+            }
+        }
+    }
+
+    /// Accumulator operation with register `v`, checked ones leave to the interpreter at `exit`
+    /// when they fail.
+    fn acc_reg(&mut self, block: Block, opcode: Opcode, v: u8, exit: Option<u32>) {
+        let acc = self.read_var(Var::Acc, block);
+        let value = self.read_var(Var::Reg(v), block);
+        self.acc_op(block, opcode, [acc, value], exit);
+    }
+
+    fn acc_imm(&mut self, block: Block, opcode: Opcode, imm: u32, exit: u32) {
+        let acc = self.read_var(Var::Acc, block);
+        let value = self.constant(block, imm);
+        self.acc_op(block, opcode, [acc, value], Some(exit));
+    }
+
+    fn acc_op(&mut self, block: Block, opcode: Opcode, inputs: [Inst; 2], exit: Option<u32>) {
+        let data = match exit {
+            Some(exit) => InstData::Checked {
+                opcode,
+                inputs,
+                exit,
+            },
+            None => InstData::Binary { opcode, inputs },
+        };
+        let result = self.append(block, data);
+        self.define(Var::Acc, block, result);
+    }
+}
+
+impl Tail {
+    fn targets(&self) -> &[Target] {
+        match self {
+            Tail::Jump(target) => std::slice::from_ref(target),
+            Tail::Branch(_, _, targets) => targets,
+        }
+    }
+
+    fn targets_mut(&mut self) -> &mut [Target] {
+        match self {
+            Tail::Jump(target) => std::slice::from_mut(target),
+            Tail::Branch(_, _, targets) => targets,
+        }
+    }
+}
+
+/// Bytecode blocks of the code reachable from instruction `entry` by their first instruction,
+/// with the end of their lowered instructions and the way they end. The entry instruction can be
+/// in the middle of a bytecode block.
+fn region(code: &[bytecode::Inst], entry: usize) -> BTreeMap<usize, (usize, Tail)> {
+    let cfg = build_cfg(code);
+    let block_end = |pc: usize| cfg.insts(cfg.block_of(pc).unwrap()).end;
+
+    let mut tails: BTreeMap<usize, (usize, Tail)> = BTreeMap::new();
+    let mut worklist = vec![entry];
+    while let Some(start) = worklist.pop() {
+        if tails.contains_key(&start) {
+            continue;
+        }
+        let (end, tail) = split_block(code, start, block_end(start));
+        for target in tail.targets() {
+            if let Target::Block(pc) = target {
+                worklist.push(*pc);
+            }
+        }
+        tails.insert(start, (end, tail));
+    }
+    tails
+}
+
+/// Translate the code reachable from instruction `entry` into SSA form. Control flow stays in
+/// the function of `entry`: calls and returns exit to the interpreter.
+pub fn lower(code: &[bytecode::Inst], entry: usize) -> Function {
+    assert!(entry < code.len(), "Entry {} is out of the code", entry);
+    build(code, entry, region(code, entry))
+}
+
+/// Translate the loop with the header `header` into SSA form for on-stack replacement: the code
+/// is entered at the header in the middle of the execution of the loop, its entry block loads
+/// the registers and the accumulator the loop reads from the interpreter frame and edges leaving
+/// the loop exit to the interpreter. The loop consists of the blocks reachable from the header
+/// which lead back to it, so the code of an inner loop also covers the loops around it.
+pub fn lower_loop(code: &[bytecode::Inst], header: usize) -> Function {
+    assert!(header < code.len(), "Header {} is out of the code", header);
+    let mut tails = region(code, header);
+
+    let mut preds: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (start, (_, tail)) in &tails {
+        for target in tail.targets() {
+            if let Target::Block(pc) = target {
+                preds.entry(*pc).or_default().push(*start);
+            }
+        }
+    }
+    // The header dominates the region so every edge to it is a back edge
+    let mut blocks: BTreeSet<usize> = BTreeSet::new();
+    blocks.insert(header);
+    let mut worklist = preds.get(&header).cloned().unwrap_or_default();
+    while let Some(block) = worklist.pop() {
+        if blocks.insert(block) {
+            worklist.extend(preds.get(&block).into_iter().flatten());
+        }
+    }
+
+    tails.retain(|start, _| blocks.contains(start));
+    for (_, tail) in tails.values_mut() {
+        for target in tail.targets_mut() {
+            if let Target::Block(pc) = *target {
+                if !blocks.contains(&pc) {
+                    *target = Target::Exit(pc as u32);
+                }
+            }
+        }
+    }
+    build(code, header, tails)
+}
+
+/// Build the function of the bytecode blocks `tails` entered at instruction `entry`.
+fn build(code: &[bytecode::Inst], entry: usize, tails: BTreeMap<usize, (usize, Tail)>) -> Function {
+    // Branches need blocks to exit from
+    let mut exits: BTreeSet<u32> = BTreeSet::new();
+    for (_, tail) in tails.values() {
+        if let Tail::Branch(..) = tail {
+            for target in tail.targets() {
+                if let Target::Exit(pc) = target {
+                    exits.insert(*pc);
+                }
+            }
+        }
+    }
+
+    let mut builder = Builder {
+        code,
+        func: Function::new(),
+        entry: 0,
+        defs: HashMap::new(),
+        incomplete_phis: HashMap::new(),
+        sealed: HashSet::new(),
+        filled: HashSet::new(),
+        replaced: HashMap::new(),
+    };
+    let mut blocks: HashMap<usize, Block> = HashMap::new();
+    let mut exit_blocks: HashMap<u32, Block> = HashMap::new();
+    let mut next_block = 0;
+    builder.func.layout.append_block(next_block);
+    for start in tails.keys() {
+        next_block += 1;
+        blocks.insert(*start, next_block);
+        builder.func.layout.append_block(next_block);
+    }
+    for pc in &exits {
+        next_block += 1;
+        exit_blocks.insert(*pc, next_block);
+        builder.func.layout.append_block(next_block);
+    }
+    let dest = |target: Target| match target {
+        Target::Block(pc) => blocks[&pc],
+        Target::Exit(pc) => exit_blocks[&pc],
+    };
+
+    builder.func.add_edge(builder.entry, blocks[&entry]);
+    for (start, (_, tail)) in &tails {
+        match tail {
+            Tail::Jump(Target::Block(pc)) => builder.func.add_edge(blocks[start], blocks[pc]),
+            Tail::Jump(Target::Exit(_)) => (),
+            Tail::Branch(_, _, targets) => {
+                for target in targets {
+                    builder.func.add_edge(blocks[start], dest(*target));
+                }
+            }
+        }
+    }
+
+    builder.seal(builder.entry);
+    builder.filled.insert(builder.entry);
+    for (start, (end, tail)) in &tails {
+        let block = blocks[start];
+        builder.try_seal(block);
+        builder.fill(block, *start, *end);
+
+        let data = match tail {
+            Tail::Jump(Target::Block(pc)) => InstData::Jump {
+                opcode: Opcode::Jump,
+                dest: blocks[pc],
+            },
+            Tail::Jump(Target::Exit(pc)) => InstData::Exit {
+                opcode: Opcode::Exit,
+                pc: *pc,
+            },
+            Tail::Branch(opcode, regs, targets) => {
+                let inputs = [
+                    builder.read_var(Var::Reg(regs[0]), block),
+                    builder.read_var(Var::Reg(regs[1]), block),
+                ];
+                InstData::Branch {
+                    opcode: *opcode,
+                    inputs,
+                    succs: [dest(targets[0]), dest(targets[1])],
+                }
+            }
+        };
+        builder.append(block, data);
+        builder.filled.insert(block);
+
+        let succs: Vec<Block> = builder.func.succs(block).collect();
+        for succ in succs {
+            builder.try_seal(succ);
+        }
+    }
+    for (pc, block) in &exit_blocks {
+        builder.seal(*block);
+        builder.append(
+            *block,
+            InstData::Exit {
+                opcode: Opcode::Exit,
+                pc: *pc,
+            },
+        );
+    }
+
+    let dest = blocks[&entry];
+    builder.append(
+        builder.entry,
+        InstData::Jump {
+            opcode: Opcode::Jump,
+            dest,
+        },
+    );
+    debug_verify(&builder.func);
+    builder.func
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+    use crate::bytecode::Inst as B;
+    use crate::jit::lower::{lower, lower_loop};
+    use crate::jit::{Function, InstData, Opcode, Var};
+
+    /// Opcodes of the instructions of `block`.
+    fn opcodes(func: &Function, block: u32) -> Vec<Opcode> {
+        func.layout()
+            .block_insts(block)
+            .map(|inst| func.dfg().inst_data(inst).opcode())
+            .collect()
+    }
+
+    #[test]
+    fn fibonacci() {
+        let program = assemble("fibonacci.S", include_str!("../../examples/fibonacci.S")).unwrap();
+        let func = lower(&program.code, 0);
+        let dfg = func.dfg();
+
+        assert_eq!(func.layout().blocks().collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+        // Everything is defined before the loop, nothing is loaded
+        assert_eq!(opcodes(&func, 0), vec![Opcode::Jump]);
+        assert_eq!(func.preds(2).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(func.succs(2).collect::<Vec<_>>(), vec![2, 3]);
+
+        // v1, v2 and v3 change in the loop, v0 stays zero
+        let phis: Vec<u32> = func
+            .layout()
+            .block_insts(2)
+            .filter(|inst| dfg.inst_data(*inst).opcode() == Opcode::Phi)
+            .collect();
+        assert_eq!(phis.len(), 3);
+        let mut initial = Vec::new();
+        for phi in &phis {
+            let inputs = dfg.inst_data(*phi).inputs();
+            assert_eq!(inputs.len(), 2);
+            match dfg.inst_data(inputs[0]) {
+                InstData::Constant { value, .. } => initial.push(*value),
+                data => panic!("{:?} is not a constant", data),
+            }
+            assert_eq!(func.layout().inst_block(inputs[1]), Some(2));
+        }
+        initial.sort_unstable();
+        assert_eq!(initial, vec![0, 1, 6]);
+
+        let last = func.layout().last_inst(2).unwrap();
+        match dfg.inst_data(last) {
+            InstData::Branch {
+                opcode: Opcode::Bne,
+                inputs,
+                succs: [2, 3],
+            } => {
+                // v2 was decremented and v0 is the constant set before the loop
+                assert_eq!(dfg.inst_data(inputs[0]).opcode(), Opcode::Sub);
+                assert_eq!(
+                    dfg.inst_data(inputs[1]),
+                    &InstData::Constant {
+                        opcode: Opcode::Constant,
+                        value: 0
+                    }
+                );
+            }
+            data => panic!("{:?} does not end the loop", data),
+        }
+        assert_eq!(
+            opcodes(&func, 3),
+            vec![Opcode::Exit],
+            "leaving the loop halts"
+        );
+    }
+
+    #[test]
+    fn loads_and_exits() {
+        // Loop entered in the middle with the counter and the accumulator from the interpreter
+        let code = vec![
+            B::Movi(0, 3),
+            B::Movi(1, 0),
+            B::Addi(2),
+            B::Dec(0),
+            B::Bne(0, 1, 2),
+            B::Call(0, 0),
+            B::Print,
+        ];
+        let func = lower(&code, 3);
+        let dfg = func.dfg();
+
+        // The entry is the second half of the loop body
+        assert_eq!(
+            opcodes(&func, 0),
+            vec![Opcode::Load, Opcode::Load, Opcode::Load, Opcode::Jump]
+        );
+        let loaded: Vec<Var> = func
+            .layout()
+            .block_insts(0)
+            .filter_map(|inst| match dfg.inst_data(inst) {
+                InstData::Load { var, .. } => Some(*var),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(loaded, vec![Var::Reg(0), Var::Reg(1), Var::Acc]);
+
+        // The loop is split into two blocks at the entry, the accumulator and v0 change in it
+        assert_eq!(func.layout().blocks().count(), 4);
+        assert_eq!(
+            opcodes(&func, 1),
+            vec![
+                Opcode::Phi,
+                Opcode::Phi,
+                Opcode::Constant,
+                Opcode::Add,
+                Opcode::Store,
+                Opcode::Constant,
+                Opcode::Sub,
+                Opcode::Store,
+                Opcode::Bne,
+            ]
+        );
+        assert_eq!(
+            opcodes(&func, 2),
+            vec![Opcode::Constant, Opcode::Sub, Opcode::Store, Opcode::Bne]
+        );
+        assert_eq!(func.preds(1).collect::<Vec<_>>(), vec![1, 2]);
+
+        // The call is left to the interpreter
+        let exit = func.layout().first_inst(3).unwrap();
+        assert_eq!(
+            dfg.inst_data(exit),
+            &InstData::Exit {
+                opcode: Opcode::Exit,
+                pc: 5
+            }
+        );
+    }
+
+    #[test]
+    fn loop_entry() {
+        let code = vec![
+            B::Movi(0, 3),
+            B::Movi(1, 0),
+            B::Dec(0),
+            B::Bne(0, 1, 2),
+            B::Ldai(7),
+            B::Print,
+        ];
+        // The code after the loop is part of the region but not of the loop
+        assert_eq!(
+            opcodes(&lower(&code, 2), 2),
+            vec![Opcode::Constant, Opcode::Store, Opcode::Print, Opcode::Exit]
+        );
+
+        let func = lower_loop(&code, 2);
+        assert_eq!(func.layout().blocks().collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(
+            opcodes(&func, 0),
+            vec![Opcode::Load, Opcode::Load, Opcode::Jump]
+        );
+        assert_eq!(func.preds(1).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(func.succs(1).collect::<Vec<_>>(), vec![1, 2]);
+        let exit = func.layout().first_inst(2).unwrap();
+        assert_eq!(
+            func.dfg().inst_data(exit),
+            &InstData::Exit {
+                opcode: Opcode::Exit,
+                pc: 4
+            }
+        );
+
+        // The inner loop is entered in the middle of the outer one which it leads back to
+        let program = assemble(
+            "loop_release_37_seconds.S",
+            include_str!("../../examples/loop_release_37_seconds.S"),
+        )
+        .unwrap();
+        let func = lower_loop(&program.code, 4);
+        let loads: Vec<Var> = func
+            .layout()
+            .block_insts(0)
+            .filter_map(|inst| match func.dfg().inst_data(inst) {
+                InstData::Load { var, .. } => Some(*var),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(loads, vec![Var::Reg(1), Var::Reg(0), Var::Reg(2)]);
+        assert_eq!(func.layout().blocks().count(), 5);
+    }
+}
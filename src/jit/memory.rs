@@ -0,0 +1,90 @@
+//! Executable memory for machine code.
+//!
+//! The code is copied to pages mapped writable and the pages are then made executable and read
+//! only, so they are never writable and executable at the same time.
+
+use std::ffi::c_void;
+use std::io;
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 2;
+const MAP_ANONYMOUS: i32 = 0x20;
+const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+extern "C" {
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: i32,
+        flags: i32,
+        fd: i32,
+        offset: i64,
+    ) -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut c_void, len: usize) -> i32;
+}
+
+/// Pages holding machine code, they are unmapped when dropped.
+pub struct ExecutableMemory {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl ExecutableMemory {
+    /// Map pages with a copy of `code` and make them executable.
+    pub fn new(code: &[u8]) -> io::Result<Self> {
+        // mmap rounds the length up to whole pages
+        let len = code.len().max(1);
+        let ptr = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                len,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let memory = Self { ptr, len };
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(code.as_ptr(), ptr as *mut u8, code.len());
+            if mprotect(ptr, len, PROT_READ | PROT_EXEC) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(memory)
+    }
+
+    /// Address of the first byte of the code.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr as *const u8
+    }
+}
+
+impl Drop for ExecutableMemory {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.ptr, self.len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jit::memory::ExecutableMemory;
+
+    #[test]
+    fn execute() {
+        // mov eax, 42; ret
+        let memory = ExecutableMemory::new(&[0xb8, 42, 0, 0, 0, 0xc3]).unwrap();
+        let f: extern "C" fn() -> u64 = unsafe { std::mem::transmute(memory.as_ptr()) };
+
+        assert_eq!(f(), 42);
+    }
+}
@@ -0,0 +1,387 @@
+//! Linear scan register allocation.
+//!
+//! The instructions are numbered in the order of the layout and every value gets a single live
+//! interval from the first to the last position it is live at, holes included, as in Poletto and
+//! Sarkar, "Linear Scan Register Allocation". The intervals are scanned by their start, a value
+//! gets a free register or, when there is none, the interval ending last is spilled to a stack
+//! slot for its whole lifetime.
+//!
+//! Phis of a block start together at its first position so they never share a register. Their
+//! inputs are copied on the edges with `parallel_moves`.
+//!
+//! The allocator does not know the target: registers are the indices `0..regs` which the code
+//! generator maps to machine registers.
+
+use std::collections::HashMap;
+
+use super::liveness::{phi_inputs, Liveness};
+use super::{Function, Inst, InstData};
+
+/// Where a value lives while it is live.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Location {
+    Reg(usize),
+    /// Index of a stack slot
+    Stack(usize),
+}
+
+/// Positions from `start` to `end` including both.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Interval {
+    pub value: Inst,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Interval {
+    pub fn overlaps(&self, other: &Interval) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+}
+
+/// Live intervals of the values of `func` sorted by their start.
+pub fn intervals(func: &Function, liveness: &Liveness) -> Vec<Interval> {
+    let layout = &func.layout;
+    let mut positions: HashMap<Inst, usize> = HashMap::new();
+    let mut ranges: HashMap<Inst, (usize, usize)> = HashMap::new();
+    let mut extend = |value: Inst, position: usize| {
+        let range = ranges.entry(value).or_insert((position, position));
+        range.0 = range.0.min(position);
+        range.1 = range.1.max(position);
+    };
+
+    let mut position = 0;
+    let mut bounds = Vec::new();
+    for block in layout.blocks() {
+        let start = position;
+        for inst in layout.block_insts(block) {
+            positions.insert(inst, position);
+            position += 1;
+        }
+        bounds.push((block, start, position - 1));
+    }
+
+    for (block, start, end) in bounds.iter().copied() {
+        for value in liveness.live_in(block) {
+            extend(*value, start);
+        }
+        for value in liveness.live_out(block) {
+            extend(*value, end);
+        }
+        for inst in layout.block_insts(block) {
+            let data = func.dfg.inst_data(inst);
+            match data {
+                InstData::Phi { .. } => extend(inst, start),
+                _ => {
+                    if data.has_result() {
+                        extend(inst, positions[&inst]);
+                    }
+                    for input in data.inputs() {
+                        extend(*input, positions[&inst]);
+                    }
+                }
+            }
+        }
+        for succ in func.succs(block) {
+            for (_, input) in phi_inputs(func, block, succ) {
+                extend(input, end);
+            }
+        }
+    }
+
+    let mut intervals: Vec<Interval> = ranges
+        .into_iter()
+        .map(|(value, (start, end))| Interval { value, start, end })
+        .collect();
+    intervals.sort_unstable_by_key(|interval| (interval.start, interval.value));
+    intervals
+}
+
+pub struct Allocation {
+    locations: HashMap<Inst, Location>,
+    intervals: Vec<Interval>,
+    stack_slots: usize,
+}
+
+impl Allocation {
+    /// Allocate the registers `0..regs` to the values of `func`.
+    pub fn compute(func: &Function, regs: usize) -> Self {
+        let liveness = Liveness::compute(func);
+        let intervals = intervals(func, &liveness);
+        let mut locations = HashMap::new();
+        let mut stack_slots = 0;
+        let mut spill = |value: Inst, locations: &mut HashMap<Inst, Location>| {
+            locations.insert(value, Location::Stack(stack_slots));
+            stack_slots += 1;
+        };
+
+        // Free registers with the lowest one last and the intervals holding a register
+        let mut free: Vec<usize> = (0..regs).rev().collect();
+        let mut active: Vec<(Interval, usize)> = Vec::new();
+        for interval in intervals.iter().copied() {
+            // Instructions read their inputs before writing their result, so the result can take
+            // the register of an input which dies there. Phis of a block all start at its first
+            // position and are written together, they never share registers.
+            let reuse = !matches!(func.dfg.inst_data(interval.value), InstData::Phi { .. });
+            active.retain(|(other, reg)| {
+                let expired = other.end < interval.start || reuse && other.end == interval.start;
+                if expired {
+                    free.push(*reg);
+                }
+                !expired
+            });
+            free.sort_unstable_by(|a, b| b.cmp(a));
+
+            if let Some(reg) = free.pop() {
+                locations.insert(interval.value, Location::Reg(reg));
+                active.push((interval, reg));
+                continue;
+            }
+            // Spill the interval which is live the longest
+            let last = active
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, (other, _))| (other.end, other.value))
+                .map(|(i, _)| i);
+            match last {
+                Some(i) if active[i].0.end > interval.end => {
+                    let (spilled, reg) = active.swap_remove(i);
+                    spill(spilled.value, &mut locations);
+                    locations.insert(interval.value, Location::Reg(reg));
+                    active.push((interval, reg));
+                }
+                _ => spill(interval.value, &mut locations),
+            }
+        }
+
+        Self {
+            locations,
+            intervals,
+            stack_slots,
+        }
+    }
+
+    pub fn location(&self, value: Inst) -> Location {
+        self.locations[&value]
+    }
+
+    /// Intervals of the values sorted by their start.
+    pub fn intervals(&self) -> &[Interval] {
+        &self.intervals
+    }
+
+    /// Number of stack slots holding spilled values.
+    pub fn stack_slots(&self) -> usize {
+        self.stack_slots
+    }
+
+    /// Registers holding values.
+    pub fn used_regs(&self) -> impl Iterator<Item = usize> + '_ {
+        let mut regs: Vec<usize> = self
+            .locations
+            .values()
+            .filter_map(|location| match location {
+                Location::Reg(reg) => Some(*reg),
+                Location::Stack(_) => None,
+            })
+            .collect();
+        regs.sort_unstable();
+        regs.dedup();
+        regs.into_iter()
+    }
+}
+
+/// Order the simultaneous copies `moves` from sources to distinct destinations so copying them
+/// one after the other has the same effect. Copies in a cycle are broken up by saving a
+/// destination to `temp` first, which must not be one of the locations of `moves`.
+pub fn parallel_moves<L: Copy + PartialEq>(moves: &[(L, L)], temp: L) -> Vec<(L, L)> {
+    let mut pending: Vec<(L, L)> = moves
+        .iter()
+        .copied()
+        .filter(|(src, dst)| src != dst)
+        .collect();
+    let mut sequence = Vec::new();
+    while !pending.is_empty() {
+        // A destination which no other pending copy reads can be written
+        let ready = pending
+            .iter()
+            .position(|(_, dst)| !pending.iter().any(|(src, _)| src == dst));
+        match ready {
+            Some(i) => sequence.push(pending.remove(i)),
+            None => {
+                // Every destination is read, so the copies form cycles
+                let dst = pending[0].1;
+                sequence.push((dst, temp));
+                for (src, _) in pending.iter_mut() {
+                    if *src == dst {
+                        *src = temp;
+                    }
+                }
+            }
+        }
+    }
+    sequence
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::assembler::assemble;
+    use crate::jit::liveness::Liveness;
+    use crate::jit::lower::lower;
+    use crate::jit::regalloc::{intervals, parallel_moves, Allocation, Interval, Location};
+    use crate::jit::InstData;
+
+    #[test]
+    fn loop_intervals() {
+        let program = assemble(
+            "loop_release_37_seconds.S",
+            include_str!("../../examples/loop_release_37_seconds.S"),
+        )
+        .unwrap();
+        let func = lower(&program.code, 0);
+        let intervals = intervals(&func, &Liveness::compute(&func));
+        let interval = |value| *intervals.iter().find(|i| i.value == value).unwrap();
+
+        // The zero compared with lives from block1 to the branch of block4 at position 18, the
+        // phis start at the headers
+        assert_eq!(
+            interval(1),
+            Interval {
+                value: 1,
+                start: 1,
+                end: 18
+            }
+        );
+        assert_eq!(
+            interval(6),
+            Interval {
+                value: 6,
+                start: 6,
+                end: 8
+            }
+        );
+        assert_eq!(
+            interval(13),
+            Interval {
+                value: 13,
+                start: 13,
+                end: 15
+            }
+        );
+        // The outer counter is live through the inner loop
+        assert_eq!(
+            interval(8),
+            Interval {
+                value: 8,
+                start: 8,
+                end: 18
+            }
+        );
+
+        // With two registers the values living the longest are spilled, the inner loop keeps
+        // its values in registers and v15 takes the register of its input v14
+        let alloc = Allocation::compute(&func, 2);
+        assert_eq!(alloc.location(1), Location::Stack(0));
+        assert_eq!(alloc.location(8), Location::Stack(1));
+        assert_eq!(alloc.location(13), Location::Reg(1));
+        assert_eq!(alloc.location(14), Location::Reg(0));
+        assert_eq!(alloc.location(15), Location::Reg(0));
+        assert_eq!(alloc.stack_slots(), 2);
+
+        let alloc = Allocation::compute(&func, 8);
+        assert_eq!(alloc.stack_slots(), 0);
+        assert_eq!(alloc.used_regs().count(), 4);
+    }
+
+    #[test]
+    fn no_conflicts() {
+        for source in [
+            include_str!("../../examples/fibonacci.S"),
+            include_str!("../../examples/loop_release_37_seconds.S"),
+        ]
+        .iter()
+        {
+            let program = assemble("test.S", source).unwrap();
+            for entry in 0..program.code.len() {
+                let func = lower(&program.code, entry);
+                for regs in 0..=8 {
+                    let alloc = Allocation::compute(&func, regs);
+                    let intervals = alloc.intervals();
+                    for (i, a) in intervals.iter().enumerate() {
+                        for b in &intervals[i + 1..] {
+                            let (x, y) = (alloc.location(a.value), alloc.location(b.value));
+                            // Results may take the registers of inputs dying at them
+                            let reuses = a.end == b.start
+                                && !matches!(func.dfg.inst_data(b.value), InstData::Phi { .. });
+                            assert!(
+                                x != y
+                                    || matches!(x, Location::Reg(_)) && (!a.overlaps(b) || reuses),
+                                "v{} and v{} share {:?} with {} registers\n{}",
+                                a.value,
+                                b.value,
+                                x,
+                                regs,
+                                func
+                            );
+                        }
+                        if let Location::Reg(reg) = alloc.location(a.value) {
+                            assert!(reg < regs);
+                        }
+                    }
+                    if regs == 0 {
+                        assert_eq!(alloc.stack_slots(), intervals.len());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Apply the copies one after the other to `state`.
+    fn apply(state: &mut HashMap<char, u32>, moves: &[(char, char)]) {
+        for (src, dst) in moves {
+            let value = state[src];
+            state.insert(*dst, value);
+        }
+    }
+
+    #[test]
+    fn parallel() {
+        for moves in [
+            vec![('a', 'b'), ('b', 'a')],
+            vec![('a', 'b'), ('b', 'c'), ('c', 'a')],
+            vec![('a', 'b'), ('a', 'c'), ('c', 'd'), ('d', 'e')],
+            vec![
+                ('a', 'b'),
+                ('b', 'a'),
+                ('c', 'd'),
+                ('d', 'c'),
+                ('a', 'e'),
+                ('e', 'f'),
+            ],
+            vec![('a', 'a'), ('b', 'c')],
+        ]
+        .iter()
+        {
+            let initial: HashMap<char, u32> = "abcdeft".chars().map(|c| (c, c as u32)).collect();
+            let sequence = parallel_moves(moves, 't');
+
+            let mut state = initial.clone();
+            apply(&mut state, &sequence);
+            for (src, dst) in moves {
+                assert_eq!(state[dst], initial[src], "{:?}: {:?}", moves, sequence);
+            }
+            for c in "abcdef".chars() {
+                if !moves.iter().any(|(_, dst)| *dst == c) {
+                    assert_eq!(state[&c], initial[&c]);
+                }
+            }
+        }
+        assert_eq!(parallel_moves(&[('a', 'a')], 't'), vec![]);
+        assert_eq!(
+            parallel_moves(&[('a', 'b'), ('b', 'a')], 't'),
+            vec![('b', 't'), ('a', 'b'), ('t', 'a')]
+        );
+    }
+}
@@ -0,0 +1,697 @@
+//! Textual form of the SSA IR.
+//!
+//! A function is printed as its blocks in layout order, every block lists its instructions:
+//!
+//! ```text
+//! function {
+//! block0:
+//!     v1 = load r0
+//!     jump block1
+//!
+//! block1:  ; preds: block0, block1
+//!     v2 = phi v1, v4
+//!     v3 = iconst 1
+//!     v4 = sub v2, v3, exit 7
+//!     store r0, v4
+//!     bne v4, v3, block1, block2
+//!
+//! block2:  ; preds: block1
+//!     exit 9
+//! }
+//! ```
+//!
+//! Values are named after the instructions defining them, instructions without a result are not
+//! named. The inputs of a phi follow the predecessors of its block which are sorted by number.
+//! Checked arithmetic and prints name the bytecode index they exit to when they fail, registers
+//! of the frame are `r0`, `r1`, ... and the accumulator is `acc`. Comments start with `;`.
+//!
+//! `parse_function` reads the format back and keeps the value and block numbers, so printing a
+//! parsed function reproduces the text without the comments.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use super::{Block, DataFlowGraph, Function, Inst, InstData, Opcode, Var};
+
+impl Opcode {
+    /// Name of the opcode in the textual IR.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Constant => "iconst",
+            Self::Add => "add",
+            Self::Sub => "sub",
+            Self::Mul => "mul",
+            Self::Div => "div",
+            Self::Mod => "mod",
+            Self::Neg => "neg",
+            Self::And => "and",
+            Self::Or => "or",
+            Self::Xor => "xor",
+            Self::Not => "not",
+            Self::Shl => "shl",
+            Self::Shr => "shr",
+            Self::Sar => "sar",
+            Self::Beq => "beq",
+            Self::Bne => "bne",
+            Self::Blt => "blt",
+            Self::Ble => "ble",
+            Self::Bgt => "bgt",
+            Self::Bge => "bge",
+            Self::Bltu => "bltu",
+            Self::Bleu => "bleu",
+            Self::Bgtu => "bgtu",
+            Self::Bgeu => "bgeu",
+            Self::Jump => "jump",
+            Self::Exit => "exit",
+            Self::Phi => "phi",
+            Self::Load => "load",
+            Self::Store => "store",
+            Self::Print => "print",
+        }
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl fmt::Display for Var {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Reg(reg) => write!(f, "r{}", reg),
+            Self::Acc => f.write_str("acc"),
+        }
+    }
+}
+
+/// Write `inst` without indentation and line break.
+fn write_inst(f: &mut fmt::Formatter, dfg: &DataFlowGraph, inst: Inst) -> fmt::Result {
+    let data = dfg.inst_data(inst);
+    if data.has_result() {
+        write!(f, "v{} = ", inst)?;
+    }
+    write!(f, "{}", data.opcode())?;
+
+    match data {
+        InstData::Constant { value, .. } => write!(f, " {}", value),
+        InstData::Unary { input, .. } => write!(f, " v{}", input),
+        InstData::Binary { inputs, .. } => write!(f, " v{}, v{}", inputs[0], inputs[1]),
+        InstData::Checked { inputs, exit, .. } => {
+            write!(f, " v{}, v{}, exit {}", inputs[0], inputs[1], exit)
+        }
+        InstData::Branch { inputs, succs, .. } => write!(
+            f,
+            " v{}, v{}, block{}, block{}",
+            inputs[0], inputs[1], succs[0], succs[1]
+        ),
+        InstData::Jump { dest, .. } => write!(f, " block{}", dest),
+        InstData::Exit { pc, .. } => write!(f, " {}", pc),
+        InstData::Phi { inputs, .. } => {
+            for (i, input) in inputs.iter().enumerate() {
+                write!(f, "{} v{}", if i == 0 { "" } else { "," }, input)?;
+            }
+            Ok(())
+        }
+        InstData::Load { var, .. } => write!(f, " {}", var),
+        InstData::Print { input, exit, .. } => write!(f, " v{}, exit {}", input, exit),
+        InstData::Store { var, input, .. } => write!(f, " {}, v{}", var, input),
+    }
+}
+
+/// Text of an instruction, see `DataFlowGraph::display_inst`.
+pub struct DisplayInst<'a> {
+    dfg: &'a DataFlowGraph,
+    inst: Inst,
+}
+
+impl<'a> fmt::Display for DisplayInst<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_inst(f, self.dfg, self.inst)
+    }
+}
+
+impl DataFlowGraph {
+    /// Display `inst` as a line of the textual IR without indentation.
+    pub fn display_inst(&self, inst: Inst) -> DisplayInst<'_> {
+        DisplayInst { dfg: self, inst }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "function {{")?;
+        for (i, block) in self.layout.blocks().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "block{}:", block)?;
+            let preds: Vec<String> = self.preds(block).map(|p| format!("block{}", p)).collect();
+            if !preds.is_empty() {
+                write!(f, "  ; preds: {}", preds.join(", "))?;
+            }
+            writeln!(f)?;
+
+            for inst in self.layout.block_insts(block) {
+                writeln!(f, "    {}", self.dfg.display_inst(inst))?;
+            }
+        }
+        writeln!(f, "}}")
+    }
+}
+
+/// Error in the textual IR, `line` starts from 1.
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn opcode(name: &str) -> Option<Opcode> {
+    let opcode = match name {
+        "iconst" => Opcode::Constant,
+        "add" => Opcode::Add,
+        "sub" => Opcode::Sub,
+        "mul" => Opcode::Mul,
+        "div" => Opcode::Div,
+        "mod" => Opcode::Mod,
+        "neg" => Opcode::Neg,
+        "and" => Opcode::And,
+        "or" => Opcode::Or,
+        "xor" => Opcode::Xor,
+        "not" => Opcode::Not,
+        "shl" => Opcode::Shl,
+        "shr" => Opcode::Shr,
+        "sar" => Opcode::Sar,
+        "beq" => Opcode::Beq,
+        "bne" => Opcode::Bne,
+        "blt" => Opcode::Blt,
+        "ble" => Opcode::Ble,
+        "bgt" => Opcode::Bgt,
+        "bge" => Opcode::Bge,
+        "bltu" => Opcode::Bltu,
+        "bleu" => Opcode::Bleu,
+        "bgtu" => Opcode::Bgtu,
+        "bgeu" => Opcode::Bgeu,
+        "jump" => Opcode::Jump,
+        "exit" => Opcode::Exit,
+        "phi" => Opcode::Phi,
+        "load" => Opcode::Load,
+        "store" => Opcode::Store,
+        "print" => Opcode::Print,
+        _ => return None,
+    };
+    Some(opcode)
+}
+
+/// Number following `prefix` in an operand such as `v12` or `block3`.
+fn numbered<T: std::str::FromStr>(operand: &str, prefix: &str, what: &str) -> Result<T, String> {
+    operand
+        .strip_prefix(prefix)
+        .filter(|digits| digits.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|digits| digits.parse().ok())
+        .ok_or_else(|| format!("expected {}, found `{}`", what, operand))
+}
+
+fn value(operand: &str) -> Result<Inst, String> {
+    numbered(operand, "v", "a value")
+}
+
+fn block(operand: &str) -> Result<Block, String> {
+    numbered(operand, "block", "a block")
+}
+
+fn number<T: std::str::FromStr>(operand: &str) -> Result<T, String> {
+    numbered(operand, "", "a number")
+}
+
+fn var(operand: &str) -> Result<Var, String> {
+    if operand == "acc" {
+        Ok(Var::Acc)
+    } else {
+        numbered(operand, "r", "a register or `acc`").map(Var::Reg)
+    }
+}
+
+/// Bytecode index of an operand such as `exit 12`.
+fn exit(operand: &str) -> Result<u32, String> {
+    let pc = operand
+        .strip_prefix("exit")
+        .filter(|pc| pc.starts_with(char::is_whitespace))
+        .ok_or_else(|| format!("expected `exit`, found `{}`", operand))?;
+    number(pc.trim())
+}
+
+/// Parse the instruction of `line` into its result and data.
+fn parse_inst(line: &str) -> Result<(Option<Inst>, InstData), String> {
+    let (result, rest) = match line.find('=') {
+        Some(eq) => (Some(value(line[..eq].trim())?), line[eq + 1..].trim()),
+        None => (None, line),
+    };
+    let (name, rest) = rest.split_at(rest.find(char::is_whitespace).unwrap_or(rest.len()));
+    let opcode = opcode(name).ok_or_else(|| format!("unknown opcode `{}`", name))?;
+    let operands: Vec<&str> = match rest.trim() {
+        "" => Vec::new(),
+        rest => rest.split(',').map(str::trim).collect(),
+    };
+    let count = |n: usize| {
+        if operands.len() == n {
+            Ok(())
+        } else {
+            Err(format!(
+                "`{}` takes {} operands, found {}",
+                name,
+                n,
+                operands.len()
+            ))
+        }
+    };
+
+    let data = match opcode {
+        Opcode::Constant => {
+            count(1)?;
+            InstData::Constant {
+                opcode,
+                value: number(operands[0])?,
+            }
+        }
+        Opcode::Neg | Opcode::Not => {
+            count(1)?;
+            InstData::Unary {
+                opcode,
+                input: value(operands[0])?,
+            }
+        }
+        Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Div | Opcode::Mod
+            if operands.len() == 3 =>
+        {
+            InstData::Checked {
+                opcode,
+                inputs: [value(operands[0])?, value(operands[1])?],
+                exit: exit(operands[2])?,
+            }
+        }
+        Opcode::Add
+        | Opcode::Sub
+        | Opcode::Mul
+        | Opcode::Div
+        | Opcode::Mod
+        | Opcode::And
+        | Opcode::Or
+        | Opcode::Xor
+        | Opcode::Shl
+        | Opcode::Shr
+        | Opcode::Sar => {
+            count(2)?;
+            InstData::Binary {
+                opcode,
+                inputs: [value(operands[0])?, value(operands[1])?],
+            }
+        }
+        Opcode::Beq
+        | Opcode::Bne
+        | Opcode::Blt
+        | Opcode::Ble
+        | Opcode::Bgt
+        | Opcode::Bge
+        | Opcode::Bltu
+        | Opcode::Bleu
+        | Opcode::Bgtu
+        | Opcode::Bgeu => {
+            count(4)?;
+            InstData::Branch {
+                opcode,
+                inputs: [value(operands[0])?, value(operands[1])?],
+                succs: [block(operands[2])?, block(operands[3])?],
+            }
+        }
+        Opcode::Jump => {
+            count(1)?;
+            InstData::Jump {
+                opcode,
+                dest: block(operands[0])?,
+            }
+        }
+        Opcode::Exit => {
+            count(1)?;
+            InstData::Exit {
+                opcode,
+                pc: number(operands[0])?,
+            }
+        }
+        Opcode::Phi => InstData::Phi {
+            opcode,
+            inputs: operands
+                .iter()
+                .map(|operand| value(operand))
+                .collect::<Result<_, _>>()?,
+        },
+        Opcode::Print => {
+            count(2)?;
+            InstData::Print {
+                opcode,
+                input: value(operands[0])?,
+                exit: exit(operands[1])?,
+            }
+        }
+        Opcode::Load => {
+            count(1)?;
+            InstData::Load {
+                opcode,
+                var: var(operands[0])?,
+            }
+        }
+        Opcode::Store => {
+            count(2)?;
+            InstData::Store {
+                opcode,
+                var: var(operands[0])?,
+                input: value(operands[1])?,
+            }
+        }
+    };
+
+    match (result, data.has_result()) {
+        (Some(_), false) => Err(format!("`{}` does not define a value", name)),
+        (None, true) => Err(format!("`{}` defines a value which is not named", name)),
+        _ => Ok((result, data)),
+    }
+}
+
+/// Parse a function printed by `Function`'s `Display` implementation. The blocks and the values
+/// keep their numbers, the CFG edges are taken from the terminators.
+pub fn parse_function(text: &str) -> Result<Function, ParseError> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.split(';').next().unwrap().trim()))
+        .filter(|(_, line)| !line.is_empty());
+    let error = |line: usize, message: String| ParseError { line, message };
+
+    match lines.next() {
+        Some((_, "function {")) => (),
+        Some((line, text)) => {
+            return Err(error(
+                line,
+                format!("expected `function {{`, found `{}`", text),
+            ))
+        }
+        None => return Err(error(1, "expected `function {`".to_string())),
+    }
+
+    let mut blocks: Vec<Block> = Vec::new();
+    // Instructions with their line and block
+    let mut insts: Vec<(usize, Block, Option<Inst>, InstData)> = Vec::new();
+    let mut closed = false;
+    for (line, text) in &mut lines {
+        if text == "}" {
+            closed = true;
+            break;
+        }
+        if let Some(name) = text.strip_suffix(':') {
+            let block = block(name.trim()).map_err(|message| error(line, message))?;
+            if blocks.contains(&block) {
+                return Err(error(line, format!("block{} is defined twice", block)));
+            }
+            blocks.push(block);
+            continue;
+        }
+
+        let (result, data) = parse_inst(text).map_err(|message| error(line, message))?;
+        match blocks.last() {
+            Some(block) => insts.push((line, *block, result, data)),
+            None => return Err(error(line, "instruction outside of a block".to_string())),
+        }
+    }
+    if let Some((line, text)) = lines.next() {
+        return Err(error(
+            line,
+            format!("unexpected `{}` after the function", text),
+        ));
+    }
+    if !closed {
+        return Err(error(text.lines().count() + 1, "expected `}`".to_string()));
+    }
+
+    let mut defined: HashSet<Inst> = HashSet::new();
+    for (line, _, result, _) in &insts {
+        if let Some(value) = result {
+            if !defined.insert(*value) {
+                return Err(error(*line, format!("v{} is defined twice", value)));
+            }
+        }
+    }
+    for (line, _, _, data) in &insts {
+        if let Some(input) = data.inputs().iter().find(|input| !defined.contains(input)) {
+            return Err(error(*line, format!("v{} is not defined", input)));
+        }
+        if let Some(succ) = data.succs().iter().find(|succ| !blocks.contains(succ)) {
+            return Err(error(*line, format!("block{} is not defined", succ)));
+        }
+    }
+
+    let mut func = Function::new();
+    for block in &blocks {
+        func.layout.append_block(*block);
+    }
+    // Instructions without a result are numbered after the values
+    if let Some(last) = defined.iter().max() {
+        func.dfg.next_inst = func.dfg.next_inst.max(last + 1);
+    }
+    let mut edges: HashMap<Block, Vec<Block>> = HashMap::new();
+    for (_, block, result, data) in insts {
+        edges.entry(block).or_default().extend(data.succs());
+        let inst = match result {
+            Some(value) => {
+                func.dfg.insert_inst(value, data);
+                value
+            }
+            None => func.dfg.make_inst(data),
+        };
+        func.layout.append_inst(inst, block);
+    }
+    for (from, succs) in edges {
+        for to in succs {
+            func.add_edge(from, to);
+        }
+    }
+
+    Ok(func)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+    use crate::bytecode::Inst as B;
+    use crate::jit::lower::lower;
+    use crate::jit::text::{parse_function, ParseError};
+    use crate::jit::{InstData, Opcode, Var};
+
+    /// Parse `text` and print it again.
+    fn round_trip(text: &str) -> String {
+        match parse_function(text) {
+            Ok(func) => func.to_string(),
+            Err(err) => panic!("{}\n{}", err, text),
+        }
+    }
+
+    fn error(text: &str) -> ParseError {
+        match parse_function(text) {
+            Ok(func) => panic!("parsed\n{}", func),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn golden() {
+        let program = assemble("fibonacci.S", include_str!("../../examples/fibonacci.S")).unwrap();
+        let text = lower(&program.code, 0).to_string();
+
+        assert_eq!(text, include_str!("../../tests/ir/fibonacci.ir"));
+        assert_eq!(round_trip(&text), text);
+    }
+
+    #[test]
+    fn round_trips() {
+        let code = vec![
+            B::Movi(0, 3),
+            B::Movi(1, 0),
+            B::Addi(2),
+            B::Dec(0),
+            B::Bne(0, 1, 2),
+            B::Call(0, 0),
+            B::Print,
+        ];
+        for entry in 0..code.len() {
+            let text = lower(&code, entry).to_string();
+            assert_eq!(round_trip(&text), text);
+        }
+
+        // Hand-written IR with sparse numbers, every kind of instruction and no comments
+        let text = "function {
+block3:  ; preds: block3
+    v10 = load acc
+    v11 = load r7
+    v12 = iconst 18446744073709551615
+    v5 = neg v10
+    v6 = not v5
+    v7 = shl v6, v11
+    v8 = mul v7, v12, exit 4
+    v9 = mul v7, v8
+    print v9, exit 5
+    store r7, v9
+    bltu v9, v12, block1, block3
+
+block1:  ; preds: block1, block3
+    v2 = phi v3, v9
+    v3 = phi
+    jump block1
+}
+";
+        assert_eq!(round_trip(text), text);
+    }
+
+    #[test]
+    fn parsed() {
+        let func = parse_function(
+            "
+            ; Counting down
+            function {
+            block0:
+                v1 = load r0            ; counter
+                v2 = iconst 1
+                jump block1
+            block1:
+                v3 = phi v1, v4
+                v4 = sub v3, v2, exit 3
+                store r0, v4
+                bne v4, v2, block1, block2
+            block2:
+                exit 4
+            }
+            ",
+        )
+        .unwrap();
+        let dfg = func.dfg();
+
+        assert_eq!(func.layout().blocks().collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(func.entry_block(), Some(0));
+        assert_eq!(func.preds(1).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(func.succs(1).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(
+            dfg.inst_data(1),
+            &InstData::Load {
+                opcode: Opcode::Load,
+                var: Var::Reg(0)
+            }
+        );
+        assert_eq!(
+            dfg.inst_data(4),
+            &InstData::Checked {
+                opcode: Opcode::Sub,
+                inputs: [3, 2],
+                exit: 3
+            }
+        );
+        assert_eq!(dfg.users(4).len(), 3, "phi, store and branch");
+        // The jump, store, branch and exit come after the values
+        assert_eq!(func.layout().block_insts(0).last(), Some(5));
+        assert_eq!(
+            func.layout().block_insts(2).collect::<Vec<_>>(),
+            vec![8],
+            "{}",
+            func
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let cases = [
+            ("", 1, "expected `function {`"),
+            ("block0:\n", 1, "expected `function {`, found `block0:`"),
+            ("function {\nblock0:\n    exit 0\n", 4, "expected `}`"),
+            ("function {\n}\n}\n", 3, "unexpected `}` after the function"),
+            (
+                "function {\n    exit 0\n}",
+                2,
+                "instruction outside of a block",
+            ),
+            ("function {\nb0:\n}", 2, "expected a block, found `b0`"),
+            (
+                "function {\nblock0:\nblock0:\n}",
+                3,
+                "block0 is defined twice",
+            ),
+            (
+                "function {\nblock0:\n    v1 = frob v2\n}",
+                3,
+                "unknown opcode `frob`",
+            ),
+            (
+                "function {\nblock0:\n    v1 = add v2\n}",
+                3,
+                "`add` takes 2 operands, found 1",
+            ),
+            (
+                "function {\nblock0:\n    v1 = add v1, v1, v1\n}",
+                3,
+                "expected `exit`, found `v1`",
+            ),
+            (
+                "function {\nblock0:\n    v1 = iconst -1\n}",
+                3,
+                "expected a number, found `-1`",
+            ),
+            (
+                "function {\nblock0:\n    v1 = load r256\n}",
+                3,
+                "expected a register or `acc`, found `r256`",
+            ),
+            (
+                "function {\nblock0:\n    v1 = exit 0\n}",
+                3,
+                "`exit` does not define a value",
+            ),
+            (
+                "function {\nblock0:\n    iconst 0\n}",
+                3,
+                "`iconst` defines a value which is not named",
+            ),
+            (
+                "function {\nblock0:\n    v1 = iconst 0\n    v1 = iconst 1\n}",
+                4,
+                "v1 is defined twice",
+            ),
+            (
+                "function {\nblock0:\n    print v1, exit 0\n}",
+                3,
+                "v1 is not defined",
+            ),
+            (
+                "function {\nblock0:\n    jump block1\n}",
+                3,
+                "block1 is not defined",
+            ),
+        ];
+        for (text, line, message) in cases.iter() {
+            assert_eq!(
+                error(text),
+                ParseError {
+                    line: *line,
+                    message: message.to_string()
+                },
+                "{}",
+                text
+            );
+        }
+    }
+}
@@ -0,0 +1,378 @@
+//! Checks of the invariants of the SSA form.
+//!
+//! Passes transforming a `Function` can call `debug_verify` when they are done, it runs
+//! `verify_function` in debug builds only.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+use super::dominators::DominatorTree;
+use super::{Block, Function, Inst, InstData};
+
+/// Malformed IR, instructions are named `inst<n>` and their results `v<n>`.
+#[derive(Debug, PartialEq)]
+pub enum VerifyError {
+    /// The layout contains an instruction which is not in the data flow graph.
+    NotInDfg { block: Block, inst: Inst },
+    /// The data flow graph contains an instruction which is not in the layout.
+    NotInLayout { inst: Inst },
+    /// The input is not an instruction with a result.
+    InvalidInput { inst: Inst, input: Inst },
+    /// The definition of the input does not dominate the instruction, for phis the end of the
+    /// corresponding predecessor.
+    NotDominated { inst: Inst, input: Inst },
+    /// `user` is missing from the users of `value` while using it or the other way around.
+    InvalidUsers { value: Inst, user: Inst },
+    /// The phi has a different number of inputs than its block has predecessors.
+    PhiArity {
+        inst: Inst,
+        inputs: usize,
+        preds: usize,
+    },
+    /// A terminator is followed by other instructions.
+    MisplacedTerminator { block: Block, inst: Inst },
+    /// The block does not end with a terminator.
+    MissingTerminator { block: Block },
+    /// The terminator continues with a block which is not in the layout.
+    InvalidBlock { inst: Inst, block: Block },
+    /// The successors of the block in the CFG are not those of its terminator.
+    InvalidSuccs { block: Block },
+    /// The predecessors of the block in the CFG are not the blocks which have it as successor.
+    InvalidPreds { block: Block },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NotInDfg { block, inst } => {
+                write!(
+                    f,
+                    "block{}: inst{} is not in the data flow graph",
+                    block, inst
+                )
+            }
+            Self::NotInLayout { inst } => write!(f, "inst{}: not in the layout", inst),
+            Self::InvalidInput { inst, input } => {
+                write!(f, "inst{}: v{} is not a value", inst, input)
+            }
+            Self::NotDominated { inst, input } => write!(
+                f,
+                "inst{}: the definition of v{} does not dominate the use",
+                inst, input
+            ),
+            Self::InvalidUsers { value, user } => {
+                write!(f, "v{}: the users do not agree with inst{}", value, user)
+            }
+            Self::PhiArity {
+                inst,
+                inputs,
+                preds,
+            } => write!(
+                f,
+                "inst{}: phi has {} inputs for {} predecessors",
+                inst, inputs, preds
+            ),
+            Self::MisplacedTerminator { block, inst } => write!(
+                f,
+                "block{}: terminator inst{} is not at the end",
+                block, inst
+            ),
+            Self::MissingTerminator { block } => {
+                write!(f, "block{}: does not end with a terminator", block)
+            }
+            Self::InvalidBlock { inst, block } => {
+                write!(f, "inst{}: block{} is not in the layout", inst, block)
+            }
+            Self::InvalidSuccs { block } => write!(
+                f,
+                "block{}: the successors do not agree with the terminator",
+                block
+            ),
+            Self::InvalidPreds { block } => write!(
+                f,
+                "block{}: the predecessors do not agree with the successors",
+                block
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Check that `func` is well formed: the layout and the data flow graph contain the same
+/// instructions, every block ends with its only terminator whose successors are the ones of the
+/// CFG, phis have an input per predecessor, the users are the instructions having the value as
+/// input and definitions dominate their uses. Uses in unreachable blocks are not checked for
+/// dominance.
+pub fn verify_function(func: &Function) -> Result<(), Vec<VerifyError>> {
+    let (dfg, layout) = (&func.dfg, &func.layout);
+    let domtree = DominatorTree::compute(func);
+    let mut errors = Vec::new();
+
+    // Positions of the instructions in their blocks
+    let mut positions: HashMap<Inst, usize> = HashMap::new();
+    let mut preds: HashMap<Block, BTreeSet<Block>> = HashMap::new();
+    for block in layout.blocks() {
+        let insts: Vec<Inst> = layout.block_insts(block).collect();
+        let mut succs = BTreeSet::new();
+        for (i, inst) in insts.iter().enumerate() {
+            let data = match dfg.insts.get(inst) {
+                Some(data) => data,
+                None => {
+                    errors.push(VerifyError::NotInDfg { block, inst: *inst });
+                    continue;
+                }
+            };
+            positions.insert(*inst, i);
+
+            if data.is_terminator() && i + 1 < insts.len() {
+                errors.push(VerifyError::MisplacedTerminator { block, inst: *inst });
+            }
+            for succ in data.succs() {
+                if !layout.is_block_inserted(*succ) {
+                    errors.push(VerifyError::InvalidBlock {
+                        inst: *inst,
+                        block: *succ,
+                    });
+                }
+                succs.insert(*succ);
+                preds.entry(*succ).or_default().insert(block);
+            }
+        }
+
+        let last = insts.last().and_then(|inst| dfg.insts.get(inst));
+        if !last.is_some_and(InstData::is_terminator) {
+            errors.push(VerifyError::MissingTerminator { block });
+        }
+        if func.succs(block).collect::<BTreeSet<_>>() != succs {
+            errors.push(VerifyError::InvalidSuccs { block });
+        }
+    }
+    for block in layout.blocks() {
+        if func.cfg[block].preds != preds.remove(&block).unwrap_or_default() {
+            errors.push(VerifyError::InvalidPreds { block });
+        }
+    }
+
+    let mut insts: Vec<Inst> = dfg.insts.keys().copied().collect();
+    insts.sort_unstable();
+    for inst in insts {
+        let data = dfg.inst_data(inst);
+        let block = match layout.inst_block(inst) {
+            Some(block) => block,
+            None => {
+                errors.push(VerifyError::NotInLayout { inst });
+                continue;
+            }
+        };
+        let block_preds: Vec<Block> = func.preds(block).collect();
+        if let InstData::Phi { inputs, .. } = data {
+            if inputs.len() != block_preds.len() {
+                errors.push(VerifyError::PhiArity {
+                    inst,
+                    inputs: inputs.len(),
+                    preds: block_preds.len(),
+                });
+            }
+        }
+
+        for (i, input) in data.inputs().iter().copied().enumerate() {
+            if !dfg.users(input).contains(&inst) {
+                errors.push(VerifyError::InvalidUsers {
+                    value: input,
+                    user: inst,
+                });
+            }
+            if !dfg.insts.get(&input).is_some_and(InstData::has_result) {
+                errors.push(VerifyError::InvalidInput { inst, input });
+                continue;
+            }
+            // Not in the layout, reported on its own
+            let def_block = match layout.inst_block(input) {
+                Some(def_block) => def_block,
+                None => continue,
+            };
+
+            let dominated = match data {
+                InstData::Phi { .. } => match block_preds.get(i) {
+                    Some(pred) => {
+                        !domtree.is_reachable(*pred) || domtree.dominates(def_block, *pred)
+                    }
+                    None => true,
+                },
+                _ if def_block == block => positions[&input] < positions[&inst],
+                _ => !domtree.is_reachable(block) || domtree.dominates(def_block, block),
+            };
+            if !dominated {
+                errors.push(VerifyError::NotDominated { inst, input });
+            }
+        }
+    }
+
+    let mut values: Vec<Inst> = dfg.users.map.keys().copied().collect();
+    values.sort_unstable();
+    for value in values {
+        for user in dfg.users(value) {
+            let uses = dfg
+                .insts
+                .get(user)
+                .is_some_and(|data| data.inputs().contains(&value));
+            if !uses {
+                errors.push(VerifyError::InvalidUsers { value, user: *user });
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Verify `func` in debug builds and panic with the errors and the function if it is malformed.
+pub fn debug_verify(func: &Function) {
+    if cfg!(debug_assertions) {
+        if let Err(errors) = verify_function(func) {
+            let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+            panic!("{}\n{}", errors.join("\n"), func);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+    use crate::jit::lower::{lower, lower_loop};
+    use crate::jit::text::parse_function;
+    use crate::jit::verifier::{verify_function, VerifyError};
+    use crate::jit::{InstData, Opcode};
+
+    fn errors(text: &str) -> Vec<VerifyError> {
+        verify_function(&parse_function(text).unwrap()).unwrap_err()
+    }
+
+    #[test]
+    fn lowered() {
+        for source in [
+            include_str!("../../examples/fibonacci.S"),
+            include_str!("../../examples/loop_release_37_seconds.S"),
+        ]
+        .iter()
+        {
+            let program = assemble("test.S", source).unwrap();
+            for entry in 0..program.code.len() {
+                for func in [
+                    lower(&program.code, entry),
+                    lower_loop(&program.code, entry),
+                ]
+                .iter()
+                {
+                    if let Err(errors) = verify_function(func) {
+                        panic!("{:?}\n{}", errors, func);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dominance() {
+        // v2 is defined on one side of the diamond only, the loop phi gets v4 from the back edge
+        // which is fine
+        assert_eq!(
+            errors(
+                "function {
+                block0:
+                    v1 = load r0
+                    beq v1, v1, block1, block2
+                block1:
+                    v2 = neg v1
+                    jump block2
+                block2:
+                    v3 = phi v1, v2, v4
+                    v5 = not v2
+                    v6 = not v7
+                    v7 = not v5
+                    bne v3, v3, block3, block4
+                block3:
+                    v4 = not v3
+                    jump block2
+                block4:
+                    v8 = phi v2
+                    exit 0
+                }"
+            ),
+            vec![
+                VerifyError::NotDominated { inst: 5, input: 2 },
+                VerifyError::NotDominated { inst: 6, input: 7 },
+                VerifyError::NotDominated { inst: 8, input: 2 },
+            ]
+        );
+    }
+
+    #[test]
+    fn structure() {
+        assert_eq!(
+            errors(
+                "function {
+                block0:
+                    v1 = iconst 0
+                    exit 0
+                    print v1, exit 0
+                block1:
+                    v2 = not v1
+                }"
+            ),
+            vec![
+                VerifyError::MisplacedTerminator { block: 0, inst: 3 },
+                VerifyError::MissingTerminator { block: 0 },
+                VerifyError::MissingTerminator { block: 1 },
+            ]
+        );
+
+        let mut func = parse_function(
+            "function {
+            block0:
+                v1 = iconst 0
+                jump block1
+            block1:
+                v2 = phi v1
+                exit 1
+            }",
+        )
+        .unwrap();
+        // The DFG and the layout drift apart
+        func.layout.remove_inst(1);
+        let orphan = func.dfg.make_inst(InstData::Exit {
+            opcode: Opcode::Exit,
+            pc: 0,
+        });
+        func.layout.prepend_inst(orphan + 1, 1);
+        // The users miss the phi and list the jump
+        func.dfg.users[1].clear();
+        func.dfg.users[1].insert(3);
+        // The CFG has an extra edge
+        func.add_edge(1, 1);
+        assert_eq!(
+            verify_function(&func).unwrap_err(),
+            vec![
+                VerifyError::NotInDfg {
+                    block: 1,
+                    inst: orphan + 1
+                },
+                VerifyError::InvalidSuccs { block: 1 },
+                VerifyError::InvalidPreds { block: 1 },
+                VerifyError::NotInLayout { inst: 1 },
+                VerifyError::PhiArity {
+                    inst: 2,
+                    inputs: 1,
+                    preds: 2
+                },
+                VerifyError::InvalidUsers { value: 1, user: 2 },
+                VerifyError::NotInLayout { inst: orphan },
+                VerifyError::InvalidUsers { value: 1, user: 3 },
+            ]
+        );
+    }
+}
@@ -0,0 +1,409 @@
+//! Encoder of the x86-64 instructions used by the code generator.
+//!
+//! Every instruction operates on 64-bit registers. Memory operands are a base register with a
+//! displacement, jumps are always encoded with 32-bit displacements and patched once their
+//! labels are bound.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Reg {
+    Rax,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rsp,
+    Rbp,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+impl Reg {
+    /// Bits of the register number in the ModRM byte or the opcode.
+    fn low(self) -> u8 {
+        self as u8 & 7
+    }
+
+    /// Bit of the register number in the REX prefix.
+    fn high(self) -> u8 {
+        self as u8 >> 3
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mem {
+    pub base: Reg,
+    pub disp: i32,
+}
+
+/// Register or memory operand.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+    Reg(Reg),
+    Mem(Mem),
+}
+
+impl From<Reg> for Operand {
+    fn from(reg: Reg) -> Self {
+        Self::Reg(reg)
+    }
+}
+
+impl From<Mem> for Operand {
+    fn from(mem: Mem) -> Self {
+        Self::Mem(mem)
+    }
+}
+
+/// Condition codes of `jcc`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cond {
+    O,
+    No,
+    /// Unsigned less, also carry
+    B,
+    Ae,
+    E,
+    Ne,
+    Be,
+    /// Unsigned greater
+    A,
+    S,
+    Ns,
+    P,
+    Np,
+    /// Signed less
+    L,
+    Ge,
+    Le,
+    /// Signed greater
+    G,
+}
+
+/// Two operand arithmetic, the value is the `/digit` of the immediate form.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AluOp {
+    Add = 0,
+    Or = 1,
+    And = 4,
+    Sub = 5,
+    Xor = 6,
+    Cmp = 7,
+}
+
+/// Single operand instructions of opcode `F7`, the value is their `/digit`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnaryOp {
+    Not = 2,
+    Neg = 3,
+    /// Unsigned `rdx:rax = rax * operand`
+    Mul = 4,
+    /// Unsigned division of `rdx:rax`, the quotient goes to `rax` and the remainder to `rdx`
+    Div = 6,
+}
+
+/// Shifts by `cl` of opcode `D3`, the value is their `/digit`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShiftOp {
+    Shl = 4,
+    Shr = 5,
+    Sar = 7,
+}
+
+/// Position in the code which jumps can target before it is known.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Label(usize);
+
+#[derive(Default)]
+pub struct Assembler {
+    code: Vec<u8>,
+    /// Offsets of the bound labels
+    labels: Vec<Option<usize>>,
+    /// Offsets of the 32-bit displacements to patch with the labels
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Emit an instruction with REX.W, `opcode` and a ModRM byte with `reg` in the reg field.
+    fn rm(&mut self, opcode: &[u8], reg: u8, rm: Operand) {
+        let base = match rm {
+            Operand::Reg(r) | Operand::Mem(Mem { base: r, .. }) => r,
+        };
+        self.code.push(0x48 | (reg >> 3 & 1) << 2 | base.high());
+        self.code.extend_from_slice(opcode);
+        let reg = (reg & 7) << 3;
+
+        match rm {
+            Operand::Reg(r) => self.code.push(0xc0 | reg | r.low()),
+            Operand::Mem(Mem { base, disp }) => {
+                // rbp and r13 without a displacement encode rip-relative addressing
+                let mode = if disp == 0 && base.low() != 5 {
+                    0
+                } else if disp as i8 as i32 == disp {
+                    1
+                } else {
+                    2
+                };
+                self.code.push(mode << 6 | reg | base.low());
+                // rsp and r12 need a SIB byte
+                if base.low() == 4 {
+                    self.code.push(0x24);
+                }
+                match mode {
+                    1 => self.code.push(disp as u8),
+                    2 => self.code.extend_from_slice(&disp.to_le_bytes()),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    /// `mov dst, src`
+    pub fn mov(&mut self, dst: Reg, src: impl Into<Operand>) {
+        self.rm(&[0x8b], dst as u8, src.into());
+    }
+
+    /// `mov [dst], src`
+    pub fn store(&mut self, dst: Mem, src: Reg) {
+        self.rm(&[0x89], src as u8, dst.into());
+    }
+
+    /// Move `imm` to `dst` with the shortest encoding.
+    pub fn mov_imm(&mut self, dst: Reg, imm: u64) {
+        if imm <= u32::MAX as u64 {
+            // The 32-bit move clears the upper half
+            if dst.high() != 0 {
+                self.code.push(0x41);
+            }
+            self.code.push(0xb8 | dst.low());
+            self.code.extend_from_slice(&(imm as u32).to_le_bytes());
+        } else if imm as i64 as i32 as i64 == imm as i64 {
+            self.rm(&[0xc7], 0, dst.into());
+            self.code.extend_from_slice(&(imm as i32).to_le_bytes());
+        } else {
+            self.code.push(0x48 | dst.high());
+            self.code.push(0xb8 | dst.low());
+            self.code.extend_from_slice(&imm.to_le_bytes());
+        }
+    }
+
+    /// `op dst, src`
+    pub fn alu(&mut self, op: AluOp, dst: Reg, src: impl Into<Operand>) {
+        self.rm(&[(op as u8) << 3 | 3], dst as u8, src.into());
+    }
+
+    /// `op dst, imm`
+    pub fn alu_imm(&mut self, op: AluOp, dst: Reg, imm: i32) {
+        self.rm(&[0x81], op as u8, dst.into());
+        self.code.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    /// Wrapping `dst = dst * src`.
+    pub fn imul(&mut self, dst: Reg, src: impl Into<Operand>) {
+        self.rm(&[0x0f, 0xaf], dst as u8, src.into());
+    }
+
+    pub fn unary(&mut self, op: UnaryOp, operand: impl Into<Operand>) {
+        self.rm(&[0xf7], op as u8, operand.into());
+    }
+
+    /// `op dst, cl`
+    pub fn shift(&mut self, op: ShiftOp, dst: Reg) {
+        self.rm(&[0xd3], op as u8, dst.into());
+    }
+
+    /// `test a, b`
+    pub fn test(&mut self, a: Reg, b: Reg) {
+        self.rm(&[0x85], b as u8, a.into());
+    }
+
+    /// Exchange the values of `a` and `b`.
+    pub fn xchg(&mut self, a: Reg, b: Reg) {
+        self.rm(&[0x87], b as u8, a.into());
+    }
+
+    /// `lea dst, [src]`
+    pub fn lea(&mut self, dst: Reg, src: Mem) {
+        self.rm(&[0x8d], dst as u8, src.into());
+    }
+
+    pub fn push(&mut self, reg: Reg) {
+        if reg.high() != 0 {
+            self.code.push(0x41);
+        }
+        self.code.push(0x50 | reg.low());
+    }
+
+    pub fn pop(&mut self, reg: Reg) {
+        if reg.high() != 0 {
+            self.code.push(0x41);
+        }
+        self.code.push(0x58 | reg.low());
+    }
+
+    /// Indirect call of the address in `target`.
+    pub fn call(&mut self, target: impl Into<Operand>) {
+        self.rm(&[0xff], 2, target.into());
+    }
+
+    pub fn ret(&mut self) {
+        self.code.push(0xc3);
+    }
+
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Make `label` refer to the next instruction.
+    pub fn bind(&mut self, label: Label) {
+        debug_assert!(self.labels[label.0].is_none(), "Label bound twice");
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    fn rel32(&mut self, label: Label) {
+        self.fixups.push((self.code.len(), label));
+        self.code.extend_from_slice(&[0; 4]);
+    }
+
+    pub fn jmp(&mut self, label: Label) {
+        self.code.push(0xe9);
+        self.rel32(label);
+    }
+
+    /// Jump to `label` if `cond` holds.
+    pub fn jcc(&mut self, cond: Cond, label: Label) {
+        self.code.extend_from_slice(&[0x0f, 0x80 | cond as u8]);
+        self.rel32(label);
+    }
+
+    /// Resolve the jumps and return the machine code. Every label jumped to must be bound.
+    pub fn finish(mut self) -> Vec<u8> {
+        for (offset, label) in self.fixups {
+            let target = self.labels[label.0].expect("Jump to an unbound label");
+            let rel = target as i64 - (offset as i64 + 4);
+            self.code[offset..offset + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+        }
+        self.code
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jit::x86_64::{AluOp, Assembler, Cond, Mem, Reg, ShiftOp, UnaryOp};
+
+    fn encode(f: impl FnOnce(&mut Assembler)) -> Vec<u8> {
+        let mut asm = Assembler::new();
+        f(&mut asm);
+        asm.finish()
+    }
+
+    #[test]
+    fn memory_operands() {
+        let mem = |base, disp| Mem { base, disp };
+
+        assert_eq!(
+            encode(|a| a.mov(Reg::Rax, mem(Reg::Rbx, 0))),
+            [0x48, 0x8b, 0x03]
+        );
+        assert_eq!(
+            encode(|a| a.mov(Reg::Rax, mem(Reg::Rsp, 8))),
+            [0x48, 0x8b, 0x44, 0x24, 0x08]
+        );
+        assert_eq!(
+            encode(|a| a.mov(Reg::R12, mem(Reg::Rbp, -16))),
+            [0x4c, 0x8b, 0x65, 0xf0]
+        );
+        assert_eq!(
+            encode(|a| a.store(mem(Reg::R13, 0), Reg::Rcx)),
+            [0x49, 0x89, 0x4d, 0x00]
+        );
+        assert_eq!(
+            encode(|a| a.store(mem(Reg::R12, 0x1000), Reg::R9)),
+            [0x4d, 0x89, 0x8c, 0x24, 0x00, 0x10, 0x00, 0x00]
+        );
+        assert_eq!(
+            encode(|a| a.lea(Reg::Rsp, mem(Reg::Rbp, -8))),
+            [0x48, 0x8d, 0x65, 0xf8]
+        );
+        assert_eq!(
+            encode(|a| a.call(mem(Reg::Rbx, 16))),
+            [0x48, 0xff, 0x53, 0x10]
+        );
+    }
+
+    #[test]
+    fn instructions() {
+        assert_eq!(
+            encode(|a| a.mov_imm(Reg::Rax, 5)),
+            [0xb8, 0x05, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            encode(|a| a.mov_imm(Reg::R9, u64::MAX)),
+            [0x49, 0xc7, 0xc1, 0xff, 0xff, 0xff, 0xff]
+        );
+        assert_eq!(
+            encode(|a| a.mov_imm(Reg::Rax, 1 << 32)),
+            [0x48, 0xb8, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            encode(|a| a.alu(AluOp::Add, Reg::Rax, Reg::Rcx)),
+            [0x48, 0x03, 0xc1]
+        );
+        assert_eq!(
+            encode(|a| a.alu(AluOp::Cmp, Reg::R8, Reg::Rax)),
+            [0x4c, 0x3b, 0xc0]
+        );
+        assert_eq!(
+            encode(|a| a.alu_imm(AluOp::Sub, Reg::Rsp, 24)),
+            [0x48, 0x81, 0xec, 0x18, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            encode(|a| a.imul(Reg::Rax, Reg::Rcx)),
+            [0x48, 0x0f, 0xaf, 0xc1]
+        );
+        assert_eq!(
+            encode(|a| a.unary(UnaryOp::Div, Reg::Rcx)),
+            [0x48, 0xf7, 0xf1]
+        );
+        assert_eq!(
+            encode(|a| a.unary(UnaryOp::Neg, Reg::R15)),
+            [0x49, 0xf7, 0xdf]
+        );
+        assert_eq!(
+            encode(|a| a.shift(ShiftOp::Shl, Reg::Rax)),
+            [0x48, 0xd3, 0xe0]
+        );
+        assert_eq!(encode(|a| a.test(Reg::Rax, Reg::Rax)), [0x48, 0x85, 0xc0]);
+        assert_eq!(encode(|a| a.xchg(Reg::Rdx, Reg::R10)), [0x4c, 0x87, 0xd2]);
+        assert_eq!(encode(|a| a.push(Reg::R12)), [0x41, 0x54]);
+        assert_eq!(encode(|a| a.pop(Reg::Rbx)), [0x5b]);
+    }
+
+    #[test]
+    fn jumps() {
+        let code = encode(|a| {
+            let (back, forward) = (a.new_label(), a.new_label());
+            a.bind(back);
+            a.jcc(Cond::Ne, forward);
+            a.jmp(back);
+            a.bind(forward);
+            a.ret();
+        });
+
+        assert_eq!(
+            code,
+            [0x0f, 0x85, 0x05, 0x00, 0x00, 0x00, 0xe9, 0xf5, 0xff, 0xff, 0xff, 0xc3]
+        );
+    }
+}
@@ -0,0 +1,7 @@
+pub mod assembler;
+pub mod bytecode;
+pub mod container;
+pub mod disassembler;
+pub mod jit;
+pub mod verifier;
+pub mod vm;
@@ -0,0 +1,597 @@
+//! Static checks of a program before it is executed.
+//!
+//! The code is split into regions: every function of the function table spans from its entry to
+//! the entry of the next function and the code before the first function forms the top level
+//! region. Branches have to stay in their region and execution can only run past the end of a
+//! region when it is the top level one which ends the program. Registers are checked against the
+//! frame size of the region and a dataflow analysis makes sure that every register and the
+//! accumulator is written before it is read on all the paths to the read.
+
+use std::fmt;
+
+use crate::bytecode::Inst;
+use crate::container::Program;
+use crate::disassembler::operands;
+use crate::vm::NUM_REGS;
+
+/// Verification errors, each carries the index and the instruction which is rejected.
+#[derive(Debug, PartialEq)]
+pub enum VerifyError {
+    /// The branch target is outside of the region of the branch or the call target is past the
+    /// end of the program.
+    InvalidJumpTarget { pc: usize, inst: Inst, target: u32 },
+    /// Execution continues past the end of the function without `ret`.
+    FallsOffEnd { pc: usize, inst: Inst },
+    /// The register is outside of the register window of the frame.
+    InvalidRegister { pc: usize, inst: Inst, reg: u8 },
+    /// The register can be read before it is written.
+    UndefinedRegister { pc: usize, inst: Inst, reg: u8 },
+    /// The accumulator can be read before it is written.
+    UndefinedAcc { pc: usize, inst: Inst },
+}
+
+impl VerifyError {
+    /// Index of the rejected instruction.
+    pub fn pc(&self) -> usize {
+        match self {
+            Self::InvalidJumpTarget { pc, .. }
+            | Self::FallsOffEnd { pc, .. }
+            | Self::InvalidRegister { pc, .. }
+            | Self::UndefinedRegister { pc, .. }
+            | Self::UndefinedAcc { pc, .. } => *pc,
+        }
+    }
+}
+
+/// Assembly of `inst` with the branch and call targets as indices.
+fn assembly(inst: Inst) -> String {
+    let target = match inst {
+        Inst::Call(target, _) => target.to_string(),
+        _ => inst
+            .branch_target()
+            .map(|t| t.to_string())
+            .unwrap_or_default(),
+    };
+    format!("{} {}", inst.mnemonic(), operands(inst, &target))
+        .trim_end()
+        .to_string()
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidJumpTarget { pc, inst, target } => {
+                write!(
+                    f,
+                    "{}: {}: invalid jump target {}",
+                    pc,
+                    assembly(*inst),
+                    target
+                )
+            }
+            Self::FallsOffEnd { pc, inst } => write!(
+                f,
+                "{}: {}: falls off the end of the function",
+                pc,
+                assembly(*inst)
+            ),
+            Self::InvalidRegister { pc, inst, reg } => {
+                write!(f, "{}: {}: invalid register v{}", pc, assembly(*inst), reg)
+            }
+            Self::UndefinedRegister { pc, inst, reg } => write!(
+                f,
+                "{}: {}: v{} is read before it is written",
+                pc,
+                assembly(*inst),
+                reg
+            ),
+            Self::UndefinedAcc { pc, inst } => write!(
+                f,
+                "{}: {}: the accumulator is read before it is written",
+                pc,
+                assembly(*inst)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Range of instructions executed in the same kind of frame.
+#[derive(Clone, Copy)]
+struct Region {
+    start: usize,
+    end: usize,
+    nregs: usize,
+    /// Running past the end halts the program
+    halts: bool,
+}
+
+/// Registers and accumulator written on every path to an instruction.
+#[derive(Clone, PartialEq)]
+struct Defined {
+    regs: [u64; NUM_REGS / 64],
+    acc: bool,
+}
+
+impl Defined {
+    /// State at the entry of a routine called with `argc` arguments.
+    fn entry(argc: u8) -> Defined {
+        let mut defined = Defined {
+            regs: [0; NUM_REGS / 64],
+            acc: false,
+        };
+        for reg in 0..argc {
+            defined.define(reg);
+        }
+        defined
+    }
+
+    fn define(&mut self, reg: u8) {
+        self.regs[reg as usize / 64] |= 1 << (reg % 64);
+    }
+
+    fn is_defined(&self, reg: u8) -> bool {
+        self.regs[reg as usize / 64] & (1 << (reg % 64)) != 0
+    }
+
+    /// Keep what is defined in both states.
+    fn meet(&mut self, other: &Defined) {
+        for (regs, other) in self.regs.iter_mut().zip(other.regs.iter()) {
+            *regs &= other;
+        }
+        self.acc &= other.acc;
+    }
+
+    fn transfer(&self, inst: Inst) -> Defined {
+        let mut defined = self.clone();
+        if let Some(reg) = inst.reg_def() {
+            defined.define(reg);
+        }
+        defined.acc |= inst.writes_acc();
+        defined
+    }
+}
+
+/// Region of every instruction.
+fn regions(program: &Program) -> Vec<Region> {
+    let len = program.code.len();
+    let mut functions: Vec<(usize, usize)> = program
+        .functions
+        .iter()
+        .rev()
+        .map(|f| (f.entry as usize, f.nregs as usize))
+        .collect();
+    // Like in the VM the last function wins when several start at the same instruction
+    functions.sort_by_key(|(entry, _)| *entry);
+    functions.dedup_by_key(|(entry, _)| *entry);
+
+    let mut regions = Vec::with_capacity(len);
+    let mut region = Region {
+        start: 0,
+        end: functions.first().map_or(len, |(entry, _)| *entry),
+        nregs: NUM_REGS,
+        halts: true,
+    };
+    let mut next = 0;
+    for index in 0..len {
+        while next < functions.len() && functions[next].0 == index {
+            region = Region {
+                start: index,
+                end: functions.get(next + 1).map_or(len, |(entry, _)| *entry),
+                nregs: functions[next].1,
+                halts: false,
+            };
+            next += 1;
+        }
+        region.halts = region.halts && region.end == len;
+        regions.push(region);
+    }
+    regions
+}
+
+/// Instructions control can continue with after `pc`, `None` stands for running past the end of
+/// the region. Invalid branch targets are left out.
+fn successors(code: &[Inst], region: Region, pc: usize) -> Vec<Option<usize>> {
+    let mut succs = Vec::new();
+    if let Some(target) = code[pc].branch_target() {
+        let target = target as usize;
+        if target >= region.start && target < region.end {
+            succs.push(Some(target));
+        } else if target == region.end && region.halts {
+            succs.push(None);
+        }
+    }
+    if code[pc].falls_through() {
+        succs.push(if pc + 1 < region.end {
+            Some(pc + 1)
+        } else {
+            None
+        });
+    }
+    succs
+}
+
+/// Check `program`, the errors are sorted by the index of the instruction.
+pub fn verify(program: &Program) -> Result<(), Vec<VerifyError>> {
+    let code = &program.code;
+    let len = code.len();
+    let regions = regions(program);
+    let mut errors = Vec::new();
+
+    let frame_size = |target: usize| match program
+        .functions
+        .iter()
+        .rev()
+        .find(|f| f.entry as usize == target)
+    {
+        Some(function) => function.nregs as usize,
+        None => NUM_REGS,
+    };
+
+    for (pc, inst) in code.iter().enumerate() {
+        let inst = *inst;
+        let region = regions[pc];
+
+        if let Some(target) = inst.branch_target() {
+            let target = target as usize;
+            let inside = target >= region.start && target < region.end;
+            let halts = target == region.end && region.halts;
+            if !(inside || halts) {
+                errors.push(VerifyError::InvalidJumpTarget {
+                    pc,
+                    inst,
+                    target: target as u32,
+                });
+            }
+        }
+        if let Inst::Call(target, argc) = inst {
+            if target as usize >= len {
+                errors.push(VerifyError::InvalidJumpTarget { pc, inst, target });
+            } else if argc as usize > frame_size(target as usize) {
+                errors.push(VerifyError::InvalidRegister {
+                    pc,
+                    inst,
+                    reg: argc - 1,
+                });
+            }
+        }
+
+        let mut regs = inst.reg_uses();
+        regs.extend(inst.reg_def());
+        regs.dedup();
+        for reg in regs {
+            if reg as usize >= region.nregs {
+                errors.push(VerifyError::InvalidRegister { pc, inst, reg });
+            }
+        }
+    }
+
+    // Routines start at the entry point, at functions and at call targets. The arguments of the
+    // call with the fewest of them are defined at the start of a called routine.
+    let mut entries: Vec<Option<u8>> = vec![None; len];
+    for inst in code {
+        if let Inst::Call(target, argc) = *inst {
+            if let Some(entry) = entries.get_mut(target as usize) {
+                *entry = Some(entry.map_or(argc, |other| other.min(argc)));
+            }
+        }
+    }
+    let roots = program
+        .functions
+        .iter()
+        .map(|f| f.entry as usize)
+        .chain(std::iter::once(program.entry as usize));
+    for root in roots {
+        if let Some(entry) = entries.get_mut(root) {
+            entry.get_or_insert(0);
+        }
+    }
+
+    let mut states: Vec<Option<Defined>> = vec![None; len];
+    let mut worklist = Vec::new();
+    for (pc, argc) in entries.iter().enumerate() {
+        if let Some(argc) = argc {
+            states[pc] = Some(Defined::entry(*argc));
+            worklist.push(pc);
+        }
+    }
+    while let Some(pc) = worklist.pop() {
+        let out = states[pc].as_ref().unwrap().transfer(code[pc]);
+        for succ in successors(code, regions[pc], pc).into_iter().flatten() {
+            let changed = match &mut states[succ] {
+                Some(state) => {
+                    let old = state.clone();
+                    state.meet(&out);
+                    *state != old
+                }
+                None => {
+                    states[succ] = Some(out.clone());
+                    true
+                }
+            };
+            if changed {
+                worklist.push(succ);
+            }
+        }
+    }
+
+    for (pc, state) in states.iter().enumerate() {
+        let state = match state {
+            Some(state) => state,
+            None => continue,
+        };
+        let inst = code[pc];
+        let region = regions[pc];
+
+        if inst.reads_acc() && !state.acc {
+            errors.push(VerifyError::UndefinedAcc { pc, inst });
+        }
+        let mut regs = inst.reg_uses();
+        regs.dedup();
+        for reg in regs {
+            if (reg as usize) < region.nregs && !state.is_defined(reg) {
+                errors.push(VerifyError::UndefinedRegister { pc, inst, reg });
+            }
+        }
+        let falls_off = successors(code, region, pc).contains(&None);
+        if falls_off && !region.halts {
+            errors.push(VerifyError::FallsOffEnd { pc, inst });
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        errors.sort_by_key(|e| e.pc());
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble;
+    use crate::bytecode::Inst;
+    use crate::container::Program;
+    use crate::verifier::{verify, VerifyError};
+
+    fn check(source: &str) -> Result<(), Vec<VerifyError>> {
+        verify(&assemble("test.S", source).unwrap())
+    }
+
+    #[test]
+    fn valid() {
+        check(include_str!("../examples/fibonacci.S")).unwrap();
+        check(include_str!("../examples/loop_release_37_seconds.S")).unwrap();
+        check(
+            "
+            .function main, 2
+                movi v0, 10
+                call fact, 1
+                print
+                ret
+            .end
+            .function fact, 3
+                movi v1, 0
+                bne v0, v1, recurse
+                ldai 1
+                ret
+            recurse:
+                mov v2, v0
+                dec v0
+                call fact, 1
+                mul v2
+                ret
+            .end
+            ",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn void_function() {
+        // `ret` does not read the accumulator, a function without a result never writes it
+        check(
+            "
+            .function main, 1
+                movi v0, 3
+                call f, 0
+                call g, 1
+                ret
+            .end
+            .function f, 0
+                ret
+            .end
+            .function g, 1
+                dec v0
+                ret
+            .end
+            ",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn jump_targets() {
+        let program = Program {
+            code: vec![
+                Inst::Ldai(1),
+                Inst::Jmp(5),
+                Inst::Call(5, 0),
+                Inst::Jmp(6),
+                Inst::Print,
+            ],
+            ..Program::default()
+        };
+
+        assert_eq!(
+            verify(&program),
+            Err(vec![
+                VerifyError::InvalidJumpTarget {
+                    pc: 2,
+                    inst: Inst::Call(5, 0),
+                    target: 5
+                },
+                VerifyError::InvalidJumpTarget {
+                    pc: 3,
+                    inst: Inst::Jmp(6),
+                    target: 6
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn function_bounds() {
+        assert_eq!(
+            check(
+                "
+                .function main, 1
+                    ldai 1
+                    beq v0, v0, Lout
+                    jmp Lin
+                Lin:
+                .end
+                .function f, 1
+                Lout:
+                    ldai 0
+                    ret
+                .end
+                "
+            ),
+            Err(vec![
+                VerifyError::InvalidJumpTarget {
+                    pc: 1,
+                    inst: Inst::Beq(0, 0, 3),
+                    target: 3
+                },
+                VerifyError::UndefinedRegister {
+                    pc: 1,
+                    inst: Inst::Beq(0, 0, 3),
+                    reg: 0
+                },
+                VerifyError::InvalidJumpTarget {
+                    pc: 2,
+                    inst: Inst::Jmp(3),
+                    target: 3
+                },
+            ])
+        );
+        assert_eq!(
+            check(".function main, 1\nldai 1\nprint\n.end\n"),
+            Err(vec![VerifyError::FallsOffEnd {
+                pc: 1,
+                inst: Inst::Print
+            }])
+        );
+    }
+
+    #[test]
+    fn registers() {
+        assert_eq!(
+            check(
+                "
+                .function main, 2
+                    movi v1, 1
+                    movi v2, 1
+                    call f, 2
+                    ret
+                .end
+                .function f, 1
+                    ret
+                .end
+                "
+            ),
+            Err(vec![
+                VerifyError::InvalidRegister {
+                    pc: 1,
+                    inst: Inst::Movi(2, 1),
+                    reg: 2
+                },
+                VerifyError::InvalidRegister {
+                    pc: 2,
+                    inst: Inst::Call(4, 2),
+                    reg: 1
+                },
+                VerifyError::UndefinedRegister {
+                    pc: 2,
+                    inst: Inst::Call(4, 2),
+                    reg: 0
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn messages() {
+        let errors = check(
+            "
+            .function main, 1
+                add v0
+                movi v1, 2
+                beq v0, v0, Lout
+                ret
+            .end
+            .function f, 0
+            Lout:
+                ret
+            .end
+            ",
+        )
+        .unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "0: add v0: the accumulator is read before it is written",
+                "0: add v0: v0 is read before it is written",
+                "1: movi v1, 2: invalid register v1",
+                "2: beq v0, v0, 4: invalid jump target 4",
+                "2: beq v0, v0, 4: v0 is read before it is written",
+            ]
+        );
+    }
+
+    #[test]
+    fn use_before_def() {
+        assert_eq!(
+            check(
+                "
+                .function main, 3
+                    movi v0, 1
+                    movi v1, 2
+                    beq v0, v1, Lskip
+                    movi v2, 3
+                    ldai 4
+                Lskip:
+                    add v2
+                    call f, 2
+                    print
+                    call f, 1
+                    ret
+                .end
+                .function f, 2
+                    lda v1
+                    ret
+                .end
+                "
+            ),
+            Err(vec![
+                VerifyError::UndefinedAcc {
+                    pc: 5,
+                    inst: Inst::Add(2)
+                },
+                VerifyError::UndefinedRegister {
+                    pc: 5,
+                    inst: Inst::Add(2),
+                    reg: 2
+                },
+                VerifyError::UndefinedRegister {
+                    pc: 10,
+                    inst: Inst::Lda(1),
+                    reg: 1
+                },
+            ])
+        );
+    }
+}
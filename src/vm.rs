@@ -1,151 +1,1386 @@
-mod bytecode;
-mod jit;
+use std::collections::HashMap;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+use std::ffi::c_void;
+use std::fmt;
+use std::io::{self, Write};
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+use std::rc::Rc;
 
-use std::fs::File;
-use std::io::Read;
+use crate::bytecode::Inst;
+use crate::container::Program;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+use crate::jit::codegen::{compile, CompiledCode, Context};
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+use crate::jit::lower::{lower, lower_loop};
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+use crate::verifier::verify;
 
-use bytecode::Inst;
-use jit::find_leaders;
+/// Maximum number of registers in the register window of a frame, the size of frames of code
+/// outside of functions.
+pub const NUM_REGS: usize = 256;
 
-fn fetch_insts(file: &mut File) -> Vec<Inst> {
-    let mut buffer: Vec<u8> = Vec::new();
-    file.read_to_end(&mut buffer).unwrap();
-    let mut iter = buffer.iter();
-    let mut ret = Vec::new();
+/// Default limit of frames on the call stack, see `Vm::set_max_call_depth`.
+pub const MAX_CALL_DEPTH: usize = 1024;
 
-    loop {
-        let opcode = match iter.next() {
-            Some(byte) => *byte,
-            None => break,
-        };
+/// Default number of entries and loop iterations after which functions and loops are compiled,
+/// see `Vm::set_jit_threshold`.
+pub const JIT_THRESHOLD: u32 = 1000;
 
-        if opcode == 0 {
-            let v1 = *iter.next().unwrap();
-            let v2 = *iter.next().unwrap();
-            ret.push(Inst::Mov(v1, v2));
-        } else if opcode == 1 {
-            let v = *iter.next().unwrap();
-            let imm = &mut [0, 0, 0, 0];
-            for i in 0..4 {
-                imm[i] = *iter.next().unwrap();
-            }
-
-            ret.push(Inst::Movi(v, u32::from_le_bytes(*imm)));
-        } else if opcode == 2 {
-            let imm = &mut [0, 0, 0, 0];
-            for i in 0..4 {
-                imm[i] = *iter.next().unwrap();
-            }
-
-            ret.push(Inst::Ldai(u32::from_le_bytes(*imm)));
-        } else if opcode == 3 {
-            let v = *iter.next().unwrap();
-            ret.push(Inst::Lda(v));
-        } else if opcode == 4 {
-            let v = *iter.next().unwrap();
-            ret.push(Inst::Sta(v));
-        } else if opcode == 5 {
-            let v = *iter.next().unwrap();
-            ret.push(Inst::Add(v));
-        } else if opcode == 6 {
-            let v = *iter.next().unwrap();
-            ret.push(Inst::Dec(v));
-        } else if opcode == 7 {
-            let v1 = *iter.next().unwrap();
-            let v2 = *iter.next().unwrap();
-            let imm = &mut [0, 0, 0, 0];
-            for i in 0..4 {
-                imm[i] = *iter.next().unwrap();
-            }
-
-            ret.push(Inst::Bne(v1, v2, u32::from_le_bytes(*imm)));
-        } else if opcode == 8 {
-            ret.push(Inst::Print);
-        } else {
-            panic!("Invalid opcode: {}", opcode);
+/// Runtime errors, each carries the index and the instruction which failed.
+#[derive(Debug, PartialEq)]
+pub enum VmError {
+    /// The result is greater than `u64::MAX`.
+    Overflow { pc: usize, inst: Inst },
+    /// The result is less than zero.
+    Underflow { pc: usize, inst: Inst },
+    /// The branch target is past the end of the program. A target equal to the program length is
+    /// allowed and halts the VM.
+    InvalidJumpTarget { pc: usize, inst: Inst, target: u32 },
+    /// The register is outside of the register window of the frame.
+    InvalidRegister { pc: usize, inst: Inst, reg: u8 },
+    /// The divisor of a division or a remainder is zero.
+    DivisionByZero { pc: usize, inst: Inst },
+    /// A call would exceed the maximum call depth.
+    StackOverflow { pc: usize, inst: Inst },
+    /// Writing to the output failed.
+    Output {
+        pc: usize,
+        inst: Inst,
+        kind: io::ErrorKind,
+    },
+}
+
+impl VmError {
+    /// Index of the failed instruction.
+    pub fn pc(&self) -> usize {
+        match self {
+            Self::Overflow { pc, .. }
+            | Self::Underflow { pc, .. }
+            | Self::InvalidJumpTarget { pc, .. }
+            | Self::InvalidRegister { pc, .. }
+            | Self::DivisionByZero { pc, .. }
+            | Self::StackOverflow { pc, .. }
+            | Self::Output { pc, .. } => *pc,
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Overflow { pc, inst } => write!(f, "{}: {:?}: arithmetic overflow", pc, inst),
+            Self::Underflow { pc, inst } => write!(f, "{}: {:?}: arithmetic underflow", pc, inst),
+            Self::InvalidJumpTarget { pc, inst, target } => {
+                write!(f, "{}: {:?}: invalid jump target {}", pc, inst, target)
+            }
+            Self::InvalidRegister { pc, inst, reg } => {
+                write!(f, "{}: {:?}: invalid register v{}", pc, inst, reg)
+            }
+            Self::DivisionByZero { pc, inst } => {
+                write!(f, "{}: {:?}: division by zero", pc, inst)
+            }
+            Self::StackOverflow { pc, inst } => write!(f, "{}: {:?}: stack overflow", pc, inst),
+            Self::Output { pc, inst, kind } => {
+                write!(f, "{}: {:?}: output failed: {:?}", pc, inst, kind)
+            }
         }
     }
+}
+
+impl std::error::Error for VmError {}
+
+/// Access to a register outside of the register window of the current frame.
+#[derive(Debug, PartialEq)]
+pub struct RegisterError {
+    pub reg: u8,
+    /// Number of registers in the window.
+    pub nregs: usize,
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "register v{} is outside of the frame's {} registers",
+            self.reg, self.nregs
+        )
+    }
+}
+
+impl std::error::Error for RegisterError {}
+
+/// Activation record of a call.
+struct Frame {
+    /// Index of the instruction following the call.
+    return_pc: usize,
+    /// The frame's registers are `regs[base..base + nregs]`.
+    base: usize,
+    nregs: usize,
+    /// Index of the first instruction of the function running in the frame.
+    entry: u32,
+}
+
+/// Hotness counters of code starting at bytecode indices and the code compiled from there.
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+#[derive(Default)]
+struct HotCode {
+    counters: HashMap<u32, u32>,
+    /// `None` if the JIT does not support the code
+    compiled: HashMap<u32, Option<Rc<CompiledCode>>>,
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+impl HotCode {
+    /// Count an execution of the code at `pc` and return its compiled code once the count
+    /// exceeds `threshold`, `compile` compiles it the first time.
+    fn count(
+        &mut self,
+        pc: u32,
+        threshold: u32,
+        compile: impl FnOnce() -> Option<CompiledCode>,
+    ) -> Option<Rc<CompiledCode>> {
+        let counter = self.counters.entry(pc).or_insert(0);
+        *counter = counter.saturating_add(1);
+        if *counter <= threshold {
+            return None;
+        }
+        self.compiled
+            .entry(pc)
+            .or_insert_with(|| compile().map(Rc::new))
+            .clone()
+    }
+}
+
+/// State of the tiered execution of `Vm::run`.
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+struct Tiering {
+    threshold: u32,
+    /// Compiled code relies on the program passing `verifier::verify`
+    verified: bool,
+    /// Entries and backward branches of the functions by their entry
+    functions: HotCode,
+    /// Iterations of the loops by their header, their code is entered on the back edges
+    loops: HotCode,
+}
+
+/// Interpreter state: the loaded program, the accumulator, the register file and the index of the
+/// next instruction to execute. `print` writes to `out`, the standard output by default.
+///
+/// The register file is a stack of register windows, one per frame of the call stack. A call
+/// copies its arguments from the first registers of the caller to the first registers of the
+/// callee and the callee returns its result in the accumulator.
+pub struct Vm<W: Write = io::Stdout> {
+    acc: u64,
+    regs: Vec<u64>,
+    frames: Vec<Frame>,
+    max_call_depth: usize,
+    pc: usize,
+    program: Program,
+    /// Number of registers of each function by its entry.
+    frame_sizes: HashMap<u32, usize>,
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    tiering: Option<Tiering>,
+    out: W,
+}
 
-    ret
+impl Vm {
+    /// Create a VM with an empty program printing to the standard output.
+    pub fn new() -> Self {
+        Self::with_output(io::stdout())
+    }
 }
 
-fn interpret(insts: Vec<Inst>) {
-    let mut acc: u64 = 0;
-    let mut regs: Vec<u64> = vec![0; 256];
-    let mut i = 0;
+impl<W: Write> Vm<W> {
+    /// Create a VM with an empty program printing to `out`.
+    pub fn with_output(out: W) -> Self {
+        Self {
+            acc: 0,
+            regs: vec![0; NUM_REGS],
+            frames: vec![Frame {
+                return_pc: 0,
+                base: 0,
+                nregs: NUM_REGS,
+                entry: 0,
+            }],
+            max_call_depth: MAX_CALL_DEPTH,
+            pc: 0,
+            program: Program::default(),
+            frame_sizes: HashMap::new(),
+            #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+            tiering: None,
+            out,
+        }
+    }
+
+    /// Replace the program, clear the accumulator and the registers and move to the entry point.
+    pub fn load(&mut self, program: Program) {
+        self.frame_sizes = program
+            .functions
+            .iter()
+            .map(|f| (f.entry, f.nregs as usize))
+            .collect();
+        self.program = program;
+        self.reset(self.program.entry);
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        self.set_jit_threshold(self.tiering.as_ref().map(|tiering| tiering.threshold));
+    }
+
+    /// Clear the accumulator and the call stack and move to the start of function `name`.
+    /// Returns false if the program has no such function.
+    pub fn enter(&mut self, name: &str) -> bool {
+        match self.program.functions.iter().find(|f| f.name == name) {
+            Some(function) => {
+                self.reset(function.entry);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn reset(&mut self, entry: u32) {
+        let nregs = self.frame_size(entry);
+        self.acc = 0;
+        self.regs.clear();
+        self.regs.resize(nregs, 0);
+        self.frames.truncate(1);
+        self.frames[0].nregs = nregs;
+        self.frames[0].entry = entry;
+        self.pc = entry as usize;
+    }
+
+    /// Number of registers of frames starting at `entry`.
+    fn frame_size(&self, entry: u32) -> usize {
+        match self.frame_sizes.get(&entry) {
+            Some(nregs) => *nregs,
+            None => NUM_REGS,
+        }
+    }
+
+    /// Limit the number of frames on the call stack, including the frame of the entry point.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    /// Number of frames on the call stack.
+    pub fn call_depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    fn frame(&self) -> &Frame {
+        self.frames.last().unwrap()
+    }
+
+    /// Is there no instruction left to execute?
+    pub fn is_halted(&self) -> bool {
+        self.pc >= self.program.code.len()
+    }
+
+    /// Index of `reg` of the current frame in the register file.
+    fn reg_index(&self, reg: u8) -> Result<usize, VmError> {
+        let frame = self.frame();
+        if (reg as usize) < frame.nregs {
+            Ok(frame.base + reg as usize)
+        } else {
+            Err(VmError::InvalidRegister {
+                pc: self.pc,
+                inst: self.program.code[self.pc],
+                reg,
+            })
+        }
+    }
+
+    fn read(&self, reg: u8) -> Result<u64, VmError> {
+        Ok(self.regs[self.reg_index(reg)?])
+    }
+
+    fn write(&mut self, reg: u8, value: u64) -> Result<(), VmError> {
+        let index = self.reg_index(reg)?;
+        self.regs[index] = value;
+        Ok(())
+    }
+
+    /// Finish the branch at `pc`: go to `target` if the branch is `taken` or to the next
+    /// instruction otherwise.
+    fn jump(&mut self, target: u32, taken: bool) -> Result<(), VmError> {
+        if target as usize > self.program.code.len() {
+            return Err(VmError::InvalidJumpTarget {
+                pc: self.pc,
+                inst: self.program.code[self.pc],
+                target,
+            });
+        }
+
+        if taken {
+            self.pc = target as usize;
+        } else {
+            self.pc += 1;
+        }
+        Ok(())
+    }
+
+    /// Push a frame for a call of `target` passing `argc` arguments.
+    fn call(&mut self, target: u32, argc: u8) -> Result<(), VmError> {
+        let pc = self.pc;
+        let inst = self.program.code[pc];
+
+        if target as usize >= self.program.code.len() {
+            return Err(VmError::InvalidJumpTarget { pc, inst, target });
+        }
+        if self.frames.len() >= self.max_call_depth {
+            return Err(VmError::StackOverflow { pc, inst });
+        }
+        if argc > 0 {
+            self.reg_index(argc - 1)?;
+        }
+
+        let nregs = self.frame_size(target);
+        if argc as usize > nregs {
+            return Err(VmError::InvalidRegister {
+                pc,
+                inst,
+                reg: argc - 1,
+            });
+        }
+
+        let caller = self.frame();
+        let base = caller.base + caller.nregs;
+        let args = caller.base..caller.base + argc as usize;
+        self.regs.resize(base + nregs, 0);
+        self.regs.copy_within(args, base);
+        self.frames.push(Frame {
+            return_pc: pc + 1,
+            base,
+            nregs,
+            entry: target,
+        });
+        self.pc = target as usize;
+        Ok(())
+    }
 
-    loop {
-        if i == insts.len() {
-            break;
+    /// Pop the current frame, returning from the entry point halts the VM.
+    fn ret(&mut self) {
+        if self.frames.len() == 1 {
+            self.pc = self.program.code.len();
+            return;
         }
 
-        match &insts[i] {
+        let frame = self.frames.pop().unwrap();
+        self.regs.truncate(frame.base);
+        self.pc = frame.return_pc;
+    }
+
+    /// Execute the instruction at `pc`. Returns `Ok(false)` if the program has already finished.
+    ///
+    /// Values are unsigned 64-bit integers and arithmetic is checked in every build profile: an
+    /// instruction whose result does not fit fails with `VmError::Overflow` or
+    /// `VmError::Underflow`. The only exception is `neg` which computes the two's complement and
+    /// never fails. Bitwise instructions never fail either, shift amounts are taken modulo 64 and
+    /// `sar` treats the accumulator as a signed value, as do the signed branches. A failed
+    /// instruction has no effect, `pc` keeps pointing at it.
+    pub fn step(&mut self) -> Result<bool, VmError> {
+        if self.is_halted() {
+            return Ok(false);
+        }
+
+        let pc = self.pc;
+        let inst = self.program.code[pc];
+
+        match inst {
             Inst::Mov(v1, v2) => {
-                regs[*v1 as usize] = regs[*v2 as usize];
+                let value = self.read(v2)?;
+                self.write(v1, value)?;
 
-                i = i + 1;
+                self.pc += 1;
             }
             Inst::Movi(v, imm) => {
-                regs[*v as usize] = *imm as u64;
+                self.write(v, imm as u64)?;
 
-                i = i + 1;
+                self.pc += 1;
             }
             Inst::Ldai(imm) => {
-                acc = *imm as u64;
+                self.acc = imm as u64;
 
-                i = i + 1;
+                self.pc += 1;
             }
             Inst::Lda(v) => {
-                acc = regs[*v as usize];
+                self.acc = self.read(v)?;
 
-                i = i + 1;
+                self.pc += 1;
             }
             Inst::Sta(v) => {
-                regs[*v as usize] = acc;
+                self.write(v, self.acc)?;
 
-                i = i + 1;
+                self.pc += 1;
             }
             Inst::Add(v) => {
-                acc = acc + regs[*v as usize];
+                self.acc = self
+                    .acc
+                    .checked_add(self.read(v)?)
+                    .ok_or(VmError::Overflow { pc, inst })?;
 
-                i = i + 1;
+                self.pc += 1;
             }
             Inst::Dec(v) => {
-                regs[*v as usize] = regs[*v as usize] - 1;
+                let value = self
+                    .read(v)?
+                    .checked_sub(1)
+                    .ok_or(VmError::Underflow { pc, inst })?;
+                self.write(v, value)?;
 
-                i = i + 1;
+                self.pc += 1;
             }
-            Inst::Bne(v1, v2, imm) => {
-                if regs[*v1 as usize] != regs[*v2 as usize] {
-                    i = *imm as usize;
-                } else {
-                    i = i + 1;
-                }
+            Inst::Bne(v1, v2, imm) => self.jump(imm, self.read(v1)? != self.read(v2)?)?,
+            Inst::Sub(v) => {
+                self.acc = self
+                    .acc
+                    .checked_sub(self.read(v)?)
+                    .ok_or(VmError::Underflow { pc, inst })?;
+
+                self.pc += 1;
+            }
+            Inst::Mul(v) => {
+                self.acc = self
+                    .acc
+                    .checked_mul(self.read(v)?)
+                    .ok_or(VmError::Overflow { pc, inst })?;
+
+                self.pc += 1;
+            }
+            Inst::Div(v) => {
+                self.acc = self
+                    .acc
+                    .checked_div(self.read(v)?)
+                    .ok_or(VmError::DivisionByZero { pc, inst })?;
+
+                self.pc += 1;
+            }
+            Inst::Mod(v) => {
+                self.acc = self
+                    .acc
+                    .checked_rem(self.read(v)?)
+                    .ok_or(VmError::DivisionByZero { pc, inst })?;
+
+                self.pc += 1;
+            }
+            Inst::Neg => {
+                self.acc = self.acc.wrapping_neg();
+
+                self.pc += 1;
+            }
+            Inst::Addi(imm) => {
+                self.acc = self
+                    .acc
+                    .checked_add(imm as u64)
+                    .ok_or(VmError::Overflow { pc, inst })?;
+
+                self.pc += 1;
+            }
+            Inst::Subi(imm) => {
+                self.acc = self
+                    .acc
+                    .checked_sub(imm as u64)
+                    .ok_or(VmError::Underflow { pc, inst })?;
+
+                self.pc += 1;
+            }
+            Inst::Muli(imm) => {
+                self.acc = self
+                    .acc
+                    .checked_mul(imm as u64)
+                    .ok_or(VmError::Overflow { pc, inst })?;
+
+                self.pc += 1;
+            }
+            Inst::Divi(imm) => {
+                self.acc = self
+                    .acc
+                    .checked_div(imm as u64)
+                    .ok_or(VmError::DivisionByZero { pc, inst })?;
+
+                self.pc += 1;
+            }
+            Inst::Modi(imm) => {
+                self.acc = self
+                    .acc
+                    .checked_rem(imm as u64)
+                    .ok_or(VmError::DivisionByZero { pc, inst })?;
+
+                self.pc += 1;
+            }
+            Inst::And(v) => {
+                self.acc &= self.read(v)?;
+
+                self.pc += 1;
+            }
+            Inst::Or(v) => {
+                self.acc |= self.read(v)?;
+
+                self.pc += 1;
+            }
+            Inst::Xor(v) => {
+                self.acc ^= self.read(v)?;
+
+                self.pc += 1;
             }
+            Inst::Not => {
+                self.acc = !self.acc;
+
+                self.pc += 1;
+            }
+            Inst::Shl(v) => {
+                self.acc <<= self.read(v)? % 64;
+
+                self.pc += 1;
+            }
+            Inst::Shr(v) => {
+                self.acc >>= self.read(v)? % 64;
+
+                self.pc += 1;
+            }
+            Inst::Sar(v) => {
+                self.acc = ((self.acc as i64) >> (self.read(v)? % 64)) as u64;
+
+                self.pc += 1;
+            }
+            Inst::Jmp(imm) => self.jump(imm, true)?,
+            Inst::Beq(v1, v2, imm) => self.jump(imm, self.read(v1)? == self.read(v2)?)?,
+            Inst::Blt(v1, v2, imm) => {
+                self.jump(imm, (self.read(v1)? as i64) < (self.read(v2)? as i64))?
+            }
+            Inst::Ble(v1, v2, imm) => {
+                self.jump(imm, (self.read(v1)? as i64) <= (self.read(v2)? as i64))?
+            }
+            Inst::Bgt(v1, v2, imm) => {
+                self.jump(imm, (self.read(v1)? as i64) > (self.read(v2)? as i64))?
+            }
+            Inst::Bge(v1, v2, imm) => {
+                self.jump(imm, (self.read(v1)? as i64) >= (self.read(v2)? as i64))?
+            }
+            Inst::Bltu(v1, v2, imm) => self.jump(imm, self.read(v1)? < self.read(v2)?)?,
+            Inst::Bleu(v1, v2, imm) => self.jump(imm, self.read(v1)? <= self.read(v2)?)?,
+            Inst::Bgtu(v1, v2, imm) => self.jump(imm, self.read(v1)? > self.read(v2)?)?,
+            Inst::Bgeu(v1, v2, imm) => self.jump(imm, self.read(v1)? >= self.read(v2)?)?,
+            Inst::Call(target, argc) => self.call(target, argc)?,
+            Inst::Ret => self.ret(),
             Inst::Print => {
-                println!("{}", acc);
+                writeln!(self.out, "{}", self.acc).map_err(|e| VmError::Output {
+                    pc,
+                    inst,
+                    kind: e.kind(),
+                })?;
+
+                self.pc += 1;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Execute instructions until the program finishes or fails. With tiered execution enabled
+    /// hot functions run compiled.
+    pub fn run(&mut self) -> Result<(), VmError> {
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        {
+            if self.tiering.is_some() {
+                return self.run_tiered();
+            }
+        }
+        while self.step()? {}
+        Ok(())
+    }
+
+    pub fn acc(&self) -> u64 {
+        self.acc
+    }
+
+    pub fn set_acc(&mut self, value: u64) {
+        self.acc = value;
+    }
+
+    /// Value of `reg` in the register window of the current frame, `None` if the window does not
+    /// have it. A function's window has the registers declared by its `.function` directive and
+    /// code outside of functions has `NUM_REGS` registers.
+    pub fn reg(&self, reg: u8) -> Option<u64> {
+        let frame = self.frame();
+        if (reg as usize) < frame.nregs {
+            Some(self.regs[frame.base + reg as usize])
+        } else {
+            None
+        }
+    }
+
+    /// Set `reg` in the register window of the current frame, see `reg`.
+    pub fn set_reg(&mut self, reg: u8, value: u64) -> Result<(), RegisterError> {
+        let frame = self.frame();
+        if (reg as usize) < frame.nregs {
+            let index = frame.base + reg as usize;
+            self.regs[index] = value;
+            Ok(())
+        } else {
+            Err(RegisterError {
+                reg,
+                nregs: frame.nregs,
+            })
+        }
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    pub fn output(&self) -> &W {
+        &self.out
+    }
+
+    pub fn output_mut(&mut self) -> &mut W {
+        &mut self.out
+    }
+
+    /// Consume the VM and return its output.
+    pub fn into_output(self) -> W {
+        self.out
+    }
+}
+
+/// Print callback of compiled code writing to the output of a `Vm<W>`.
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+extern "C" fn print<W: Write>(out: *mut c_void, value: u64) -> u64 {
+    let out = unsafe { &mut *(out as *mut W) };
+    writeln!(out, "{}", value).is_err() as u64
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+impl<W: Write> Vm<W> {
+    /// Execute `code` on the current frame and move to the instruction it exits to. Failing
+    /// instructions exit to themselves, so `step` reports their errors.
+    ///
+    /// # Safety
+    ///
+    /// `code` must be compiled from the region of the current instruction of the loaded program,
+    /// the program must pass `verifier::verify`.
+    pub unsafe fn run_compiled(&mut self, code: &CompiledCode) {
+        let base = self.frame().base;
+        let mut ctx = Context {
+            regs: self.regs[base..].as_mut_ptr(),
+            acc: self.acc,
+            print: print::<W>,
+            out: &mut self.out as *mut W as *mut c_void,
+        };
+        let pc = code.run(&mut ctx);
+        self.acc = ctx.acc;
+        self.pc = pc as usize;
+    }
+
+    /// Enable tiered execution: `run` counts the entries of every function and the backward
+    /// branches in it and compiles the function once the count exceeds `threshold`, its next
+    /// entries run the compiled code. The iterations of every loop are counted as well and once
+    /// they exceed `threshold` the loop is compiled and entered on its back edge, this on-stack
+    /// replacement speeds up loops running long in a single call.
+    ///
+    /// `None` interprets only, which is the default. Programs failing `verifier::verify` and code
+    /// the JIT does not support are interpreted.
+    pub fn set_jit_threshold(&mut self, threshold: Option<u32>) {
+        self.tiering = threshold.map(|threshold| Tiering {
+            threshold,
+            verified: verify(&self.program).is_ok(),
+            functions: HotCode::default(),
+            loops: HotCode::default(),
+        });
+    }
+
+    /// Count an entry or a loop iteration of the function starting at `entry` and return its
+    /// code if it is hot.
+    fn tier_up(&mut self, entry: u32) -> Option<Rc<CompiledCode>> {
+        let tiering = self.tiering.as_mut()?;
+        let (code, verified) = (&self.program.code, tiering.verified);
+        tiering.functions.count(entry, tiering.threshold, || {
+            if verified {
+                compile(&lower(code, entry as usize)).ok()
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Count an iteration of the loop with the header `header` and return its code if it is hot.
+    fn tier_up_loop(&mut self, header: u32) -> Option<Rc<CompiledCode>> {
+        let tiering = self.tiering.as_mut()?;
+        let (code, verified) = (&self.program.code, tiering.verified);
+        tiering.loops.count(header, tiering.threshold, || {
+            if verified {
+                compile(&lower_loop(code, header as usize)).ok()
+            } else {
+                None
+            }
+        })
+    }
+
+    fn run_tiered(&mut self) -> Result<(), VmError> {
+        // The compiled code of a function is entered at its first instruction only
+        let mut entered = self.pc == self.frame().entry as usize;
+        while !self.is_halted() {
+            if entered {
+                entered = false;
+                if let Some(code) = self.tier_up(self.frame().entry) {
+                    // The program is verified and the code starts where the frame's function does
+                    unsafe { self.run_compiled(&code) };
+                    continue;
+                }
+            }
 
-                i = i + 1;
+            let pc = self.pc;
+            let inst = self.program.code[pc];
+            self.step()?;
+            match inst {
+                Inst::Call(..) => entered = true,
+                Inst::Ret => (),
+                _ if self.pc <= pc => {
+                    self.tier_up(self.frame().entry);
+                    if let Some(code) = self.tier_up_loop(self.pc as u32) {
+                        // The program is verified and the loop is in the frame's function
+                        unsafe { self.run_compiled(&code) };
+                    }
+                }
+                _ => (),
             }
         }
+        Ok(())
     }
 }
 
-fn main() {
-    let _now = std::time::Instant::now();
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+
+    use crate::assembler::assemble;
+    use crate::bytecode::Inst;
+    use crate::container::{FunctionInfo, Program};
+    use crate::vm::{RegisterError, Vm, VmError};
+
+    fn run(code: Vec<Inst>) -> (Vm<Vec<u8>>, Result<(), VmError>) {
+        let mut vm = Vm::with_output(Vec::new());
+        vm.load(Program {
+            code,
+            ..Default::default()
+        });
+        let ret = vm.run();
+        (vm, ret)
+    }
+
+    #[test]
+    fn fibonacci() {
+        let mut vm = Vm::with_output(Vec::new());
+        vm.load(assemble("fibonacci.S", include_str!("../examples/fibonacci.S")).unwrap());
+        vm.run().unwrap();
+
+        assert_eq!(vm.output(), b"1\n1\n2\n3\n5\n8\n13\n");
+        assert!(vm.is_halted());
+        assert_eq!(vm.acc(), 13);
+        assert_eq!(vm.reg(1), Some(8));
+        assert_eq!(vm.reg(2), Some(0));
+        assert_eq!(vm.reg(3), Some(13));
+    }
+
+    #[test]
+    fn step() {
+        let mut vm = Vm::with_output(Vec::new());
+        vm.load(Program {
+            entry: 1,
+            code: vec![Inst::Ldai(1), Inst::Movi(0, 2), Inst::Dec(0)],
+            ..Default::default()
+        });
+        assert_eq!(vm.pc(), 1);
+
+        assert_eq!(vm.step(), Ok(true));
+        assert_eq!(vm.reg(0), Some(2));
+        assert_eq!(vm.step(), Ok(true));
+        assert_eq!(vm.reg(0), Some(1));
+        assert_eq!(vm.acc(), 0);
+
+        assert!(vm.is_halted());
+        assert_eq!(vm.step(), Ok(false));
+        assert_eq!(vm.pc(), 3);
+    }
+
+    #[test]
+    fn underflow() {
+        let (vm, ret) = run(vec![Inst::Movi(0, 1), Inst::Dec(0), Inst::Dec(0)]);
+
+        assert_eq!(
+            ret,
+            Err(VmError::Underflow {
+                pc: 2,
+                inst: Inst::Dec(0)
+            })
+        );
+        assert_eq!(vm.pc(), 2);
+        assert_eq!(vm.reg(0), Some(0));
+    }
+
+    #[test]
+    fn overflow() {
+        let (vm, ret) = run(vec![
+            Inst::Movi(0, u32::MAX),
+            Inst::Ldai(1),
+            Inst::Add(0),
+            Inst::Sta(1),
+            Inst::Lda(1),
+            Inst::Add(1),
+            Inst::Sta(1),
+            Inst::Movi(2, 32),
+            Inst::Movi(3, 0),
+            // Double v1 32 times
+            Inst::Lda(1),
+            Inst::Add(1),
+            Inst::Sta(1),
+            Inst::Dec(2),
+            Inst::Bne(2, 3, 9),
+        ]);
 
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        println!("Lexical analyzer needs 2 arguments - source file name and output file name");
-        return ();
+        assert_eq!(
+            ret,
+            Err(VmError::Overflow {
+                pc: 10,
+                inst: Inst::Add(1)
+            })
+        );
+        assert_eq!(vm.acc(), 1 << 63);
     }
 
-    let mut file = File::open(&args[1]).unwrap();
+    #[test]
+    fn invalid_jump_target() {
+        let (_, ret) = run(vec![Inst::Movi(0, 1), Inst::Bne(0, 1, 3)]);
+        assert_eq!(
+            ret,
+            Err(VmError::InvalidJumpTarget {
+                pc: 1,
+                inst: Inst::Bne(0, 1, 3),
+                target: 3
+            })
+        );
 
-    let insts = fetch_insts(&mut file);
-    // interpret(insts);
+        // Jumping right past the last instruction halts
+        let (vm, ret) = run(vec![Inst::Movi(0, 1), Inst::Bne(0, 1, 3), Inst::Ldai(1)]);
+        assert_eq!(ret, Ok(()));
+        assert_eq!(vm.acc(), 0);
+    }
+
+    /// Output which accepts `capacity` bytes and fails afterwards.
+    struct Limited {
+        capacity: usize,
+    }
+
+    impl Write for Limited {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if buf.len() > self.capacity {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "full"));
+            }
+            self.capacity -= buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn output_error() {
+        let mut vm = Vm::with_output(Limited { capacity: 2 });
+        vm.load(Program {
+            code: vec![Inst::Ldai(7), Inst::Print, Inst::Print],
+            ..Default::default()
+        });
+
+        assert_eq!(
+            vm.run(),
+            Err(VmError::Output {
+                pc: 2,
+                inst: Inst::Print,
+                kind: io::ErrorKind::WriteZero
+            })
+        );
+        assert_eq!(vm.into_output().capacity, 0);
+    }
+
+    #[test]
+    fn arithmetic() {
+        let mut vm = Vm::with_output(Vec::new());
+        vm.load(
+            assemble(
+                "test.S",
+                "movi v0, 7
+            movi v1, 3
+            ldai 100
+            sub v0
+            print
+            mul v1
+            print
+            div v0
+            print
+            mod v1
+            print
+            addi 40
+            print
+            subi 2
+            print
+            muli 3
+            print
+            divi 4
+            print
+            modi 10
+            print
+            neg
+            print
+            ",
+            )
+            .unwrap(),
+        );
+        vm.run().unwrap();
+
+        let expected = [93, 279, 39, 0, 40, 38, 114, 28, 8, u64::MAX - 7];
+        let printed = String::from_utf8(vm.into_output()).unwrap();
+        let printed: Vec<u64> = printed.lines().map(|l| l.parse().unwrap()).collect();
+        assert_eq!(printed, expected);
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let (vm, ret) = run(vec![Inst::Ldai(5), Inst::Modi(2), Inst::Div(0)]);
+
+        assert_eq!(
+            ret,
+            Err(VmError::DivisionByZero {
+                pc: 2,
+                inst: Inst::Div(0)
+            })
+        );
+        assert_eq!(vm.acc(), 1);
+
+        let (_, ret) = run(vec![Inst::Divi(0)]);
+        assert_eq!(
+            ret,
+            Err(VmError::DivisionByZero {
+                pc: 0,
+                inst: Inst::Divi(0)
+            })
+        );
+    }
+
+    #[test]
+    fn sub_underflow() {
+        let (_, ret) = run(vec![Inst::Ldai(1), Inst::Subi(2)]);
+
+        assert_eq!(
+            ret,
+            Err(VmError::Underflow {
+                pc: 1,
+                inst: Inst::Subi(2)
+            })
+        );
+    }
+
+    #[test]
+    fn bitwise() {
+        let mut vm = Vm::with_output(Vec::new());
+        vm.load(
+            assemble(
+                "test.S",
+                "movi v0, 12
+            movi v1, 10
+            movi v2, 4
+            movi v3, 68
+            lda v0
+            and v1
+            print
+            lda v0
+            or v1
+            print
+            lda v0
+            xor v1
+            print
+            lda v0
+            shl v2
+            print
+            shr v2
+            print
+            ldai 0
+            not
+            shr v3
+            print
+            neg
+            sar v3
+            print
+            not
+            print
+            ",
+            )
+            .unwrap(),
+        );
+        vm.run().unwrap();
+
+        let expected = [
+            8,
+            14,
+            6,
+            192,
+            12,
+            0x0fff_ffff_ffff_ffff,
+            0xff00_0000_0000_0000,
+            0x00ff_ffff_ffff_ffff,
+        ];
+        let printed = String::from_utf8(vm.into_output()).unwrap();
+        let printed: Vec<u64> = printed.lines().map(|l| l.parse().unwrap()).collect();
+        assert_eq!(printed, expected);
+    }
+
+    #[test]
+    fn branches() {
+        // v0 = 1, v1 = 2, v2 = -1
+        let mut code = vec![
+            Inst::Movi(0, 1),
+            Inst::Movi(1, 2),
+            Inst::Ldai(0),
+            Inst::Not,
+            Inst::Sta(2),
+        ];
+        let branches = [
+            Inst::Jmp(0),
+            Inst::Beq(0, 0, 0),
+            Inst::Blt(2, 0, 0),
+            Inst::Bltu(2, 0, 0),
+            Inst::Ble(0, 0, 0),
+            Inst::Bleu(1, 0, 0),
+            Inst::Bgt(0, 2, 0),
+            Inst::Bgtu(0, 2, 0),
+            Inst::Bge(1, 1, 0),
+            Inst::Bgeu(0, 1, 0),
+        ];
+        // Every taken branch skips the print of its number
+        for (i, branch) in branches.iter().enumerate() {
+            let target = code.len() as u32 + 3;
+            let branch = match *branch {
+                Inst::Jmp(_) => Inst::Jmp(target),
+                Inst::Beq(v1, v2, _) => Inst::Beq(v1, v2, target),
+                Inst::Blt(v1, v2, _) => Inst::Blt(v1, v2, target),
+                Inst::Bltu(v1, v2, _) => Inst::Bltu(v1, v2, target),
+                Inst::Ble(v1, v2, _) => Inst::Ble(v1, v2, target),
+                Inst::Bleu(v1, v2, _) => Inst::Bleu(v1, v2, target),
+                Inst::Bgt(v1, v2, _) => Inst::Bgt(v1, v2, target),
+                Inst::Bgtu(v1, v2, _) => Inst::Bgtu(v1, v2, target),
+                Inst::Bge(v1, v2, _) => Inst::Bge(v1, v2, target),
+                Inst::Bgeu(v1, v2, _) => Inst::Bgeu(v1, v2, target),
+                _ => unreachable!(),
+            };
+            code.extend_from_slice(&[Inst::Ldai(i as u32), branch, Inst::Print]);
+        }
+        let (vm, ret) = run(code);
+
+        assert_eq!(ret, Ok(()));
+        assert_eq!(vm.output(), b"3\n5\n7\n9\n");
+    }
+
+    #[test]
+    fn call() {
+        let fact = vec![
+            Inst::Movi(1, 0),
+            Inst::Bne(0, 1, 4),
+            Inst::Ldai(1),
+            Inst::Ret,
+            Inst::Mov(2, 0),
+            Inst::Dec(0),
+            Inst::Call(0, 1),
+            Inst::Mul(2),
+            Inst::Ret,
+        ];
+        let main = vec![Inst::Movi(0, 10), Inst::Call(0, 1), Inst::Print];
+        let mut vm = Vm::with_output(Vec::new());
+        vm.load(Program {
+            entry: fact.len() as u32,
+            code: [fact, main].concat(),
+            ..Default::default()
+        });
+        vm.run().unwrap();
+
+        assert_eq!(vm.output(), b"3628800\n");
+        assert_eq!(vm.call_depth(), 1);
+        // The callees got their own registers
+        assert_eq!(vm.reg(0), Some(10));
+        assert_eq!(vm.reg(2), Some(0));
+    }
+
+    #[test]
+    fn ret_from_entry() {
+        let (vm, ret) = run(vec![Inst::Ldai(5), Inst::Ret, Inst::Ldai(6)]);
+
+        assert_eq!(ret, Ok(()));
+        assert!(vm.is_halted());
+        assert_eq!(vm.acc(), 5);
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let mut vm = Vm::with_output(Vec::new());
+        vm.set_max_call_depth(100);
+        vm.load(Program {
+            code: vec![Inst::Addi(1), Inst::Call(0, 0)],
+            ..Default::default()
+        });
+
+        assert_eq!(
+            vm.run(),
+            Err(VmError::StackOverflow {
+                pc: 1,
+                inst: Inst::Call(0, 0)
+            })
+        );
+        assert_eq!(vm.call_depth(), 100);
+        assert_eq!(vm.acc(), 100);
+    }
+
+    #[test]
+    fn functions() {
+        let mut vm = Vm::with_output(Vec::new());
+        vm.load(
+            assemble(
+                "test.S",
+                "
+            .function sum, 2
+                lda v0
+                add v1
+                ret
+            .end
+
+            .function main, 3
+                movi v0, 2
+                movi v1, 3
+                call sum, 2
+                print
+                call sum, 3
+            .end
+            ",
+            )
+            .unwrap(),
+        );
+        assert_eq!(vm.pc(), 3);
+
+        assert_eq!(
+            vm.run(),
+            Err(VmError::InvalidRegister {
+                pc: 7,
+                inst: Inst::Call(0, 3),
+                reg: 2
+            })
+        );
+        assert_eq!(vm.output(), b"5\n");
+
+        // Registers of the frame are limited by the function
+        assert!(vm.enter("sum"));
+        assert_eq!(vm.pc(), 0);
+        vm.load(Program {
+            code: vec![Inst::Movi(1, 0)],
+            functions: vec![FunctionInfo {
+                name: "f".to_string(),
+                entry: 0,
+                nregs: 1,
+            }],
+            ..Default::default()
+        });
+        assert_eq!(
+            vm.run(),
+            Err(VmError::InvalidRegister {
+                pc: 0,
+                inst: Inst::Movi(1, 0),
+                reg: 1
+            })
+        );
+        assert!(!vm.enter("g"));
+    }
+
+    #[test]
+    fn register_window() {
+        let mut vm = Vm::with_output(Vec::new());
+        vm.load(
+            assemble(
+                "test.S",
+                "
+            .function main, 2
+                movi v1, 7
+            .end
+            ",
+            )
+            .unwrap(),
+        );
+        assert_eq!(vm.set_reg(0, 3), Ok(()));
+        assert_eq!(vm.run(), Ok(()));
+        assert!(vm.is_halted());
+        assert_eq!((vm.reg(0), vm.reg(1)), (Some(3), Some(7)));
+
+        // Registers past the window of main are not accessible
+        assert_eq!(vm.reg(2), None);
+        assert_eq!(vm.reg(5), None);
+        assert_eq!(vm.set_reg(5, 1), Err(RegisterError { reg: 5, nregs: 2 }));
+
+        // Code outside of functions has all the registers
+        vm.load(Program {
+            code: vec![Inst::Movi(255, 1)],
+            ..Default::default()
+        });
+        assert_eq!(vm.run(), Ok(()));
+        assert_eq!(vm.reg(255), Some(1));
+    }
 
-    find_leaders(insts);
+    /// Run `program` interpreted and tiered with `threshold`, the results have to be the same.
+    /// Returns the tiered VM.
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    fn tiered(program: Program, threshold: u32) -> Vm<Vec<u8>> {
+        let mut interpreter = Vm::with_output(Vec::new());
+        interpreter.load(program.clone());
+        let expected = interpreter.run();
 
-    // println!("Execution time: {} seconds", now.elapsed().as_secs());
+        let mut vm = Vm::with_output(Vec::new());
+        vm.set_jit_threshold(Some(threshold));
+        vm.load(program);
+        assert_eq!(vm.run(), expected);
+        assert_eq!(
+            String::from_utf8_lossy(vm.output()),
+            String::from_utf8_lossy(interpreter.output())
+        );
+        assert_eq!(vm.acc(), interpreter.acc());
+        assert_eq!(vm.regs, interpreter.regs);
+        vm
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    #[test]
+    fn tiered_functions() {
+        let program = assemble(
+            "test.S",
+            "
+            .function square, 1
+                lda v0
+                mul v0
+                ret
+            .end
+
+            .function twice, 2
+                movi v1, 0
+                lda v0
+            loop:
+                dec v0
+                addi 1
+                bne v0, v1, loop
+                ret
+            .end
+
+            .function main, 2
+                movi v0, 20
+                movi v1, 0
+            again:
+                call square, 1
+                print
+                call twice, 1
+                print
+                dec v0
+                bne v0, v1, again
+                ret
+            .end
+            ",
+        )
+        .unwrap();
+        let vm = tiered(program, 5);
+        assert_eq!(String::from_utf8_lossy(vm.output()).lines().count(), 40);
+
+        // square is compiled after 5 calls. The loop of twice is hot in its first call which
+        // enters its code on the back edge, the other calls run twice compiled and count only
+        // their entries. The loop of main is compiled as well, its code exits at the call.
+        let tiering = vm.tiering.as_ref().unwrap();
+        assert_eq!(tiering.functions.counters[&0], 20);
+        assert_eq!(tiering.functions.counters[&3], 1 + 6 + 19);
+        assert_eq!(tiering.loops.counters[&5], 6);
+        for entry in [0, 3, 9].iter() {
+            assert!(tiering.functions.compiled[entry].is_some());
+        }
+        for header in [5, 11].iter() {
+            assert!(tiering.loops.compiled[header].is_some());
+        }
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    #[test]
+    fn tiered_fallback() {
+        // The top level code is compiled at its first entry with a threshold of 0
+        let program = assemble("fibonacci.S", include_str!("../examples/fibonacci.S")).unwrap();
+        let vm = tiered(program, 0);
+        assert!(vm.tiering.as_ref().unwrap().functions.compiled[&0].is_some());
+
+        // v0 is read before it is written, the program does not verify and is interpreted
+        let program = assemble(
+            "test.S",
+            "
+            movi v1, 3
+        L:  lda v0
+            print
+            dec v1
+            bne v1, v0, L
+            ",
+        )
+        .unwrap();
+        let vm = tiered(program, 0);
+        let tiering = vm.tiering.as_ref().unwrap();
+        assert!(!tiering.verified);
+        assert!(tiering.functions.compiled.values().all(Option::is_none));
+        assert!(tiering.loops.compiled[&1].is_none());
+        assert_eq!(vm.output(), b"0\n0\n0\n");
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    #[test]
+    fn on_stack_replacement() {
+        // The inner loop is hot after 10 iterations, its code covers the outer loop and runs
+        // the rest of the program
+        let program = assemble(
+            "loop_release_37_seconds.S",
+            &include_str!("../examples/loop_release_37_seconds.S").replace("91615", "300"),
+        )
+        .unwrap();
+        let vm = tiered(program, 10);
+        let tiering = vm.tiering.as_ref().unwrap();
+        assert_eq!(tiering.loops.counters[&4], 11);
+        assert!(!tiering.loops.counters.contains_key(&2));
+        assert!(tiering.loops.compiled[&4].is_some());
+        assert_eq!((vm.reg(1), vm.reg(2)), (Some(0), Some(0)));
+
+        // The compiled loop prints the rest of the numbers
+        let program = assemble("fibonacci.S", include_str!("../examples/fibonacci.S")).unwrap();
+        let vm = tiered(program, 2);
+        assert_eq!(vm.output(), b"1\n1\n2\n3\n5\n8\n13\n");
+        assert_eq!(vm.tiering.as_ref().unwrap().loops.counters[&6], 3);
+
+        // The compiled loop leaves to the interpreter at the overflow which fails the same way
+        let program = assemble(
+            "test.S",
+            "
+            ldai 1
+            movi v0, 2
+        L:  mul v0
+            print
+            jmp L
+            ",
+        )
+        .unwrap();
+        let vm = tiered(program, 3);
+        assert_eq!(String::from_utf8_lossy(vm.output()).lines().count(), 63);
+        assert_eq!(vm.pc(), 2);
+    }
 }